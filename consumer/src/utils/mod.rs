@@ -1,6 +1,6 @@
 #[macro_export]
 macro_rules! s {
     ($e: tt) => {
-        String::from($e);
+        String::from($e)
     };
 }
@@ -1,26 +1,59 @@
 use postgres::{rows::Rows, types::ToSql};
-use std::thread;
+use std::{fmt, sync::OnceLock};
 
 use r2d2::Pool;
 use r2d2_postgres::{PostgresConnectionManager, TlsMode};
 use rayon::{ThreadPool, ThreadPoolBuilder};
-use serde_json::{json, Value};
+use serde_json::Value;
 
-fn build_insert_query(
+#[derive(Debug)]
+pub enum Error {
+    Postgres(postgres::Error),
+    Pool(r2d2::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Postgres(e) => write!(f, "{}", e),
+            Error::Pool(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<postgres::Error> for Error {
+    fn from(e: postgres::Error) -> Self {
+        Error::Postgres(e)
+    }
+}
+
+impl From<r2d2::Error> for Error {
+    fn from(e: r2d2::Error) -> Self {
+        Error::Pool(e)
+    }
+}
+
+/// Statement inserting `fields` of `data` in `table` and its parameters,
+/// `None` when `data` is not an object holding every field. Strings are
+/// inserted as they are, any other value as JSON.
+fn build_insert_query<S: AsRef<str>>(
     table: &str,
-    fields: Vec<&str>,
+    fields: &[S],
     data: Value,
 ) -> Option<(String, Vec<String>)> {
-    if data.is_object() && fields.len() > 0 {
+    if data.is_object() && !fields.is_empty() {
         let obj = data.as_object().expect("Object extraction failed");
         let mut vals: Vec<String> = Vec::new();
         let mut fields_concat = "".to_owned();
 
-        for k in fields.clone() {
-            let val = obj.get(k).expect("Cannot get value from map");
+        for k in fields.iter().map(|k| k.as_ref()) {
+            let val = obj.get(k)?;
 
             fields_concat = format!("{}, {}", fields_concat, k.to_owned());
-            vals.push(val.as_str().expect("Cannot parste to string").to_owned());
+            vals.push(match val.as_str() {
+                Some(v) => v.to_owned(),
+                None => val.to_string(),
+            });
         }
 
         return Some((
@@ -43,13 +76,30 @@ fn build_insert_query(
 }
 
 pub struct PG {
-    fields: Vec<&'static str>,
-    url: &'static str,
-    database: &'static str,
-    user: &'static str,
-    password: &'static str,
+    fields: Vec<String>,
     pool: Pool<PostgresConnectionManager>,
-    th_pool: ThreadPool,
+}
+
+/// Threads running `insert`, `custom` and `query` for every connection,
+/// built the first time one of them is called.
+fn th_pool() -> &'static ThreadPool {
+    static TH_POOL: OnceLock<ThreadPool> = OnceLock::new();
+
+    TH_POOL.get_or_init(|| {
+        ThreadPoolBuilder::new()
+            .num_threads(100)
+            .build()
+            .expect("Thread pool not created")
+    })
+}
+
+fn params(vals: &[String]) -> Vec<&dyn ToSql> {
+    vals.iter()
+        .map(|v| {
+            let v: &dyn ToSql = v;
+            v
+        })
+        .collect()
 }
 
 impl PG {
@@ -60,44 +110,30 @@ impl PG {
         password: &'static str,
         user: &'static str,
     ) -> Self {
-        let th = ThreadPoolBuilder::new()
-            .num_threads(100)
-            .build()
-            .expect("Thread pool not created");
+        PG::connect(
+            &format!("postgres://{}:{}@{}/{}", user, password, url, database),
+            fields.into_iter().map(String::from).collect(),
+        )
+        .expect("Postgres connection pool failed")
+    }
 
-        PG {
+    /// Pool of connections to `dsn`, inserts write `fields`.
+    pub fn connect(dsn: &str, fields: Vec<String>) -> Result<Self, Error> {
+        Ok(PG {
             fields,
-            url,
-            database,
-            password,
-            user,
-            pool: Pool::new(
-                PostgresConnectionManager::new(
-                    format!("postgres://{}:{}@{}/{}", user, password, url, database),
-                    TlsMode::None,
-                )
-                .expect("Postgres connection manager failed to create"),
-            )
-            .expect("Postgres connection pool failed"),
-            th_pool: th,
-        }
+            pool: Pool::new(PostgresConnectionManager::new(dsn, TlsMode::None)?)?,
+        })
     }
 
     pub fn insert(&self, data: Value, table: &str) {
         let (insert_statement, vals) =
-            build_insert_query(table, self.fields.clone(), data).expect("Cannot build statement");
+            build_insert_query(table, &self.fields, data).expect("Cannot build statement");
 
         let pool = self.pool.clone();
 
-        self.th_pool.spawn(move || {
+        th_pool().spawn(move || {
             let conn = pool.get().expect("Cannot get a connection");
-            let params: Vec<&ToSql> = vals
-                .iter()
-                .map(|v| {
-                    let v: &ToSql = v;
-                    v
-                })
-                .collect();
+            let params = params(&vals);
             println!("{:?}", params);
             println!("{:?}", insert_statement);
             conn.execute(&insert_statement, params.as_slice())
@@ -105,6 +141,52 @@ impl PG {
         });
     }
 
+    /// Inserts `fields` of every row in a single transaction, returns how
+    /// many rows were skipped because they are not objects with every field.
+    pub fn insert_batch<S: AsRef<str>>(
+        &self,
+        table: &str,
+        fields: &[S],
+        rows: Vec<Value>,
+    ) -> Result<usize, Error> {
+        let conn = self.pool.get()?;
+        let transaction = conn.transaction()?;
+        let mut skipped = 0;
+
+        for row in rows {
+            match build_insert_query(table, fields, row) {
+                Some((statement, vals)) => {
+                    transaction
+                        .prepare_cached(&statement)?
+                        .execute(params(&vals).as_slice())?;
+                }
+                None => skipped += 1,
+            }
+        }
+
+        transaction.commit()?;
+        Ok(skipped)
+    }
+
+    /// Runs `statement` with text parameters, returns how many rows it
+    /// changed.
+    pub fn execute(&self, statement: &str, vals: &[String]) -> Result<u64, Error> {
+        let conn = self.pool.get()?;
+        Ok(conn.execute(statement, params(vals).as_slice())?)
+    }
+
+    /// Rows returned by `query` with text parameters, every column has to be
+    /// text.
+    pub fn select(&self, query: &str, vals: &[String]) -> Result<Vec<Vec<String>>, Error> {
+        let conn = self.pool.get()?;
+        let rows = conn.query(query, params(vals).as_slice())?;
+
+        Ok(rows
+            .iter()
+            .map(|row| (0..row.len()).map(|i| row.get(i)).collect())
+            .collect())
+    }
+
     pub fn custom<F, F2>(&self, statement: &'static str, on_success: F, on_failure: F2)
     where
         F: FnOnce(String) + Send + Sync + 'static,
@@ -112,7 +194,7 @@ impl PG {
     {
         let pool = self.pool.clone();
 
-        self.th_pool.spawn(move || {
+        th_pool().spawn(move || {
             let conn = pool.get().expect("Cannot get a connection");
 
             match conn.execute(&statement, &[]) {
@@ -133,7 +215,7 @@ impl PG {
     {
         let pool = self.pool.clone();
 
-        self.th_pool.spawn(move || {
+        th_pool().spawn(move || {
             let conn = pool.get().expect("Cannot get a connection");
 
             match conn.query(&query, &[]) {
@@ -154,12 +236,12 @@ mod tests {
     #[test]
     fn build_insert_statement() {
         use crate::build_insert_query;
-        use serde_json::{json, Value};
+        use serde_json::json;
 
         let fields = vec!["__id", "type", "date", "description"];
         let (statement, vals) = build_insert_query(
             "my_table",
-            fields,
+            &fields,
             json!({"__id": "i2382832", "type": "s", "date":"2018-29-12", "description":"Not sure what the desc is."}),
         )
         .expect("Cannot get a value");
@@ -0,0 +1,510 @@
+use crossbeam::channel::{unbounded, Receiver, RecvTimeoutError, Sender};
+use redis::Commands;
+use road_postgres::PG;
+use std::{
+	collections::{HashMap, VecDeque},
+	fmt, fs, io,
+	path::PathBuf,
+	sync::Arc,
+	thread,
+	time::{Duration, Instant},
+};
+
+use crate::pipeline::Ack;
+
+#[derive(Debug)]
+pub enum CheckpointError {
+	Redis(redis::RedisError),
+	Postgres(road_postgres::Error),
+	Io(io::Error),
+}
+
+impl fmt::Display for CheckpointError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			CheckpointError::Redis(e) => write!(f, "redis: {}", e),
+			CheckpointError::Postgres(e) => write!(f, "postgres: {}", e),
+			CheckpointError::Io(e) => write!(f, "{}", e),
+		}
+	}
+}
+
+impl From<redis::RedisError> for CheckpointError {
+	fn from(e: redis::RedisError) -> Self {
+		CheckpointError::Redis(e)
+	}
+}
+
+impl From<road_postgres::Error> for CheckpointError {
+	fn from(e: road_postgres::Error) -> Self {
+		CheckpointError::Postgres(e)
+	}
+}
+
+impl From<io::Error> for CheckpointError {
+	fn from(e: io::Error) -> Self {
+		CheckpointError::Io(e)
+	}
+}
+
+/// Where a Kinesis input keeps the last sequence number it handled for each
+/// of its shards.
+pub trait CheckpointStore: Send {
+	/// Sequence number of every shard checkpointed so far.
+	fn load(&mut self) -> Result<HashMap<String, String>, CheckpointError>;
+
+	fn save(&mut self, shard_id: &str, sequence_number: &str) -> Result<(), CheckpointError>;
+}
+
+/// Keeps the checkpoints of an input in the hash
+/// `firehouse:checkpoints:<input>`.
+pub struct RedisCheckpoints {
+	con: redis::Connection,
+	key: String,
+}
+
+impl RedisCheckpoints {
+	pub fn new(con: redis::Connection, input: &str) -> Self {
+		RedisCheckpoints {
+			con,
+			key: format!("firehouse:checkpoints:{}", input),
+		}
+	}
+}
+
+impl CheckpointStore for RedisCheckpoints {
+	fn load(&mut self) -> Result<HashMap<String, String>, CheckpointError> {
+		Ok(self.con.hgetall(&self.key)?)
+	}
+
+	fn save(&mut self, shard_id: &str, sequence_number: &str) -> Result<(), CheckpointError> {
+		Ok(self.con.hset(&self.key, shard_id, sequence_number)?)
+	}
+}
+
+/// Keeps the checkpoints of every input in the `firehouse_checkpoints`
+/// table, created if needed.
+pub struct PostgresCheckpoints {
+	pg: Arc<PG>,
+	input: String,
+}
+
+impl PostgresCheckpoints {
+	pub fn new(pg: Arc<PG>, input: &str) -> Result<Self, CheckpointError> {
+		pg.execute(
+			"CREATE TABLE IF NOT EXISTS firehouse_checkpoints (
+				input TEXT NOT NULL,
+				shard_id TEXT NOT NULL,
+				sequence_number TEXT NOT NULL,
+				PRIMARY KEY (input, shard_id)
+			)",
+			&[],
+		)?;
+
+		Ok(PostgresCheckpoints {
+			pg,
+			input: s!(input),
+		})
+	}
+}
+
+impl CheckpointStore for PostgresCheckpoints {
+	fn load(&mut self) -> Result<HashMap<String, String>, CheckpointError> {
+		let rows = self.pg.select(
+			"SELECT shard_id, sequence_number FROM firehouse_checkpoints WHERE input = $1",
+			std::slice::from_ref(&self.input),
+		)?;
+
+		Ok(rows
+			.into_iter()
+			.map(|mut row| (row.swap_remove(0), row.swap_remove(0)))
+			.collect())
+	}
+
+	fn save(&mut self, shard_id: &str, sequence_number: &str) -> Result<(), CheckpointError> {
+		self.pg.execute(
+			"INSERT INTO firehouse_checkpoints (input, shard_id, sequence_number)
+			VALUES ($1, $2, $3)
+			ON CONFLICT (input, shard_id) DO UPDATE SET sequence_number = EXCLUDED.sequence_number",
+			&[self.input.clone(), s!(shard_id), s!(sequence_number)],
+		)?;
+
+		Ok(())
+	}
+}
+
+/// Keeps the checkpoints of an input in a JSON file, replaced as a whole on
+/// every save so it is never left half written.
+pub struct FileCheckpoints {
+	path: PathBuf,
+	checkpoints: HashMap<String, String>,
+}
+
+impl FileCheckpoints {
+	pub fn new(path: &str) -> Self {
+		FileCheckpoints {
+			path: PathBuf::from(path),
+			checkpoints: HashMap::new(),
+		}
+	}
+}
+
+impl CheckpointStore for FileCheckpoints {
+	fn load(&mut self) -> Result<HashMap<String, String>, CheckpointError> {
+		self.checkpoints = match fs::read(&self.path) {
+			Ok(content) => serde_json::from_slice(&content)
+				.map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+			Err(ref e) if e.kind() == io::ErrorKind::NotFound => HashMap::new(),
+			Err(e) => return Err(e.into()),
+		};
+
+		Ok(self.checkpoints.clone())
+	}
+
+	fn save(&mut self, shard_id: &str, sequence_number: &str) -> Result<(), CheckpointError> {
+		self.checkpoints.insert(s!(shard_id), s!(sequence_number));
+
+		let temporary = self.path.with_extension("tmp");
+		let content = serde_json::to_vec(&self.checkpoints).expect("Strings always serialize");
+		fs::write(&temporary, content)?;
+		fs::rename(&temporary, &self.path)?;
+
+		Ok(())
+	}
+}
+
+enum Progress {
+	/// Shard, sequence number and whether the record is the first one of
+	/// its Kinesis record.
+	Read(String, String, bool),
+	Settled(String, String, bool),
+	Load(Sender<Result<HashMap<String, String>, CheckpointError>>),
+	Flush(Sender<Result<(), CheckpointError>>),
+}
+
+/// Whether the sequence number `a` comes before `b` in their shard.
+fn before(a: &str, b: &str) -> bool {
+	(a.len(), a) < (b.len(), b)
+}
+
+/// Records of a shard read but not acked yet, in the order they were read.
+/// Records unpacked from the same aggregated record share its sequence
+/// number.
+#[derive(Default)]
+struct Shard {
+	pending: VecDeque<(String, Option<bool>)>,
+	/// Sequence number of a record that failed, the shard is read again from
+	/// it. Until it comes again the records read are those read after it.
+	rewound: Option<String>,
+}
+
+impl Shard {
+	/// Tracks a record read, unless it was read after a failed one that was
+	/// not read again yet.
+	fn read(&mut self, sequence_number: &str, first: bool) {
+		match &self.rewound {
+			Some(from) if before(sequence_number, from) || (sequence_number == from && first) => {
+				self.rewound = None;
+			}
+			Some(_) => return,
+			None => {}
+		}
+		self.pending.push_back((s!(sequence_number), None));
+	}
+
+	/// Sequence number the checkpoint moves to, if any.
+	fn settle(&mut self, sequence_number: &str, ok: bool) -> Option<String> {
+		let record = self
+			.pending
+			.iter_mut()
+			.find(|(s, ok)| s == sequence_number && ok.is_none())?;
+		record.1 = Some(ok);
+
+		let mut checkpoint = None;
+		while let Some((sequence_number, Some(ok))) = self.pending.front() {
+			if !ok {
+				self.rewound = Some(sequence_number.clone());
+				self.pending.clear();
+				break;
+			}
+			let sequence_number = sequence_number.clone();
+			self.pending.pop_front();
+			// Not past an aggregated record until all it holds is acked
+			if self
+				.pending
+				.front()
+				.is_none_or(|(next, _)| next != &sequence_number)
+			{
+				checkpoint = Some(sequence_number);
+			}
+		}
+
+		checkpoint
+	}
+}
+
+/// Moves the checkpoint of each shard forward as its records are acked by
+/// every output. Records are acked in any order but a checkpoint only
+/// moves past a record once every record read before it was acked. At the
+/// first failed one it stops and the shard is to be read again from that
+/// record, see `rewinds`.
+#[derive(Clone)]
+pub struct Checkpointer {
+	progress: Sender<Progress>,
+	rewinds: Receiver<(String, String)>,
+}
+
+impl Checkpointer {
+	/// Saves the checkpoints from a thread, at most once a second. The
+	/// thread ends once the checkpointer and all its acks are gone.
+	pub fn start(input: &str, store: Box<dyn CheckpointStore>) -> Self {
+		let (progress, r) = unbounded();
+		let (rewind, rewinds) = unbounded();
+		let input = s!(input);
+		thread::spawn(move || Checkpointer::run(input, store, r, rewind));
+
+		Checkpointer { progress, rewinds }
+	}
+
+	fn run(
+		input: String,
+		mut store: Box<dyn CheckpointStore>,
+		progress: Receiver<Progress>,
+		rewind: Sender<(String, String)>,
+	) {
+		let mut shards: HashMap<String, Shard> = HashMap::new();
+		let mut unsaved: HashMap<String, String> = HashMap::new();
+		let mut saved = Instant::now();
+
+		loop {
+			let (first, disconnected) = match progress.recv_timeout(Duration::from_secs(1)) {
+				Ok(first) => (Some(first), false),
+				Err(RecvTimeoutError::Timeout) => (None, false),
+				Err(RecvTimeoutError::Disconnected) => (None, true),
+			};
+
+			let mut loads = vec![];
+			let mut flushes = vec![];
+			for p in first.into_iter().chain(progress.try_iter()) {
+				match p {
+					Progress::Read(shard_id, sequence_number, first) => {
+						let shard = shards.entry(shard_id).or_default();
+						shard.read(&sequence_number, first);
+					}
+					Progress::Settled(shard_id, sequence_number, ok) => {
+						let shard = match shards.get_mut(&shard_id) {
+							Some(shard) if shard.rewound.is_none() => shard,
+							_ => continue,
+						};
+						if let Some(checkpoint) = shard.settle(&sequence_number, ok) {
+							unsaved.insert(shard_id.clone(), checkpoint);
+						}
+						if let Some(from) = &shard.rewound {
+							eprintln!(
+								"Input {}: record {} of {} failed, the shard is read again from it",
+								input, from, shard_id
+							);
+							let _ = rewind.send((shard_id, from.clone()));
+						}
+					}
+					Progress::Load(reply) => loads.push(reply),
+					Progress::Flush(reply) => flushes.push(reply),
+				}
+			}
+
+			let due = saved.elapsed() >= Duration::from_secs(1);
+			let mut failure = None;
+			if disconnected || !loads.is_empty() || !flushes.is_empty() || due {
+				unsaved.retain(|shard_id, sequence_number| {
+					match store.save(shard_id, sequence_number) {
+						Ok(()) => false,
+						Err(e) => {
+							eprintln!("Input {} cannot save its checkpoint: {}", input, e);
+							failure = Some(e);
+							true
+						}
+					}
+				});
+				saved = Instant::now();
+			}
+			for reply in loads {
+				let _ = reply.send(store.load());
+			}
+			for reply in flushes {
+				let _ = reply.send(failure.take().map_or(Ok(()), Err));
+			}
+			if disconnected {
+				return;
+			}
+		}
+	}
+
+	/// Checkpoints saved so far, including those of shards another worker
+	/// read before handing them over.
+	pub fn load(&self) -> Result<HashMap<String, String>, CheckpointError> {
+		let (reply, loaded) = unbounded();
+		self.progress
+			.send(Progress::Load(reply))
+			.expect("Checkpoint thread stopped");
+
+		loaded.recv().expect("Checkpoint thread stopped")
+	}
+
+	/// Saves the checkpoints moved so far right away, for the acks of every
+	/// record read to count they must have been settled before.
+	pub fn flush(&self) -> Result<(), CheckpointError> {
+		let (reply, flushed) = unbounded();
+		self.progress
+			.send(Progress::Flush(reply))
+			.expect("Checkpoint thread stopped");
+
+		flushed.recv().expect("Checkpoint thread stopped")
+	}
+
+	/// Shards to read again from the sequence number given, that of their
+	/// first record that failed. Records read meanwhile are not tracked.
+	pub fn rewinds(&self) -> Receiver<(String, String)> {
+		self.rewinds.clone()
+	}
+
+	/// Ack of a record just read from `shard_id`, the first one of its
+	/// Kinesis record or one unpacked from it after the first.
+	pub fn track(&self, shard_id: &str, sequence_number: &str, first: bool) -> Ack {
+		let progress = self.progress.clone();
+		let (shard_id, sequence_number) = (s!(shard_id), s!(sequence_number));
+		self.progress
+			.send(Progress::Read(
+				shard_id.clone(),
+				sequence_number.clone(),
+				first,
+			))
+			.expect("Checkpoint thread stopped");
+
+		Ack::new(move |ok| {
+			let _ = progress.send(Progress::Settled(shard_id, sequence_number, ok));
+		})
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{CheckpointError, CheckpointStore, Checkpointer, FileCheckpoints, Shard};
+	use crossbeam::channel::{unbounded, Sender};
+	use std::{collections::HashMap, env, fs, time::Duration};
+
+	#[test]
+	fn moves_past_records_once_every_previous_one_is_acked() {
+		let mut shard = Shard::default();
+		for sequence_number in &["1", "2", "3", "4"] {
+			shard.pending.push_back((sequence_number.to_string(), None));
+		}
+
+		assert_eq!(None, shard.settle("2", true));
+		assert_eq!(Some(s!("2")), shard.settle("1", true));
+		assert_eq!(None, shard.settle("4", true));
+		assert_eq!(None, shard.settle("3", false));
+		assert_eq!(Some(s!("3")), shard.rewound);
+		assert!(shard.pending.is_empty());
+	}
+
+	#[test]
+	fn tracks_records_again_once_read_again_from_the_failed_one() {
+		let mut shard = Shard::default();
+		shard.read("1", true);
+		shard.read("2", true);
+		assert_eq!(Some(s!("1")), shard.settle("1", true));
+		assert_eq!(None, shard.settle("2", false));
+
+		// Read before the shard was read again
+		shard.read("2", false);
+		shard.read("3", true);
+		assert!(shard.pending.is_empty());
+
+		shard.read("2", true);
+		shard.read("3", true);
+		assert_eq!(None, shard.rewound);
+		assert_eq!(None, shard.settle("3", true));
+		assert_eq!(Some(s!("3")), shard.settle("2", true));
+	}
+
+	#[test]
+	fn moves_past_aggregated_records_once_all_they_hold_is_acked() {
+		let mut shard = Shard::default();
+		for sequence_number in &["1", "2", "2", "3"] {
+			shard.pending.push_back((sequence_number.to_string(), None));
+		}
+
+		assert_eq!(Some(s!("1")), shard.settle("1", true));
+		assert_eq!(None, shard.settle("2", true));
+		assert_eq!(Some(s!("2")), shard.settle("2", true));
+		assert_eq!(Some(s!("3")), shard.settle("3", true));
+		assert!(shard.pending.is_empty());
+	}
+
+	struct Saves(Sender<(String, String)>);
+
+	impl CheckpointStore for Saves {
+		fn load(&mut self) -> Result<HashMap<String, String>, CheckpointError> {
+			Ok(HashMap::new())
+		}
+
+		fn save(&mut self, shard_id: &str, sequence_number: &str) -> Result<(), CheckpointError> {
+			self.0.send((s!(shard_id), s!(sequence_number))).unwrap();
+			Ok(())
+		}
+	}
+
+	#[test]
+	fn saves_once_outputs_ack() {
+		let (s, saves) = unbounded();
+		let checkpointer = Checkpointer::start("test", Box::new(Saves(s)));
+		let first = checkpointer.track("shard-0", "10", true);
+		let second = checkpointer.track("shard-0", "11", true);
+		let other = checkpointer.track("shard-1", "5", true);
+
+		second.ack();
+		other.nack();
+		assert!(saves.recv_timeout(Duration::from_millis(100)).is_err());
+		assert_eq!(
+			Ok((s!("shard-1"), s!("5"))),
+			checkpointer.rewinds().recv_timeout(Duration::from_secs(1))
+		);
+
+		first.ack();
+		checkpointer.flush().unwrap();
+		assert_eq!(Ok((s!("shard-0"), s!("11"))), saves.try_recv());
+
+		let third = checkpointer.track("shard-0", "12", true);
+		third.ack();
+		checkpointer.track("shard-1", "6", true).ack();
+		checkpointer.track("shard-1", "5", true).ack();
+		checkpointer.track("shard-1", "6", true).ack();
+		drop(checkpointer);
+		let mut saved = saves.iter().take(2).collect::<Vec<(String, String)>>();
+		saved.sort();
+		assert_eq!(
+			vec![(s!("shard-0"), s!("12")), (s!("shard-1"), s!("6"))],
+			saved
+		);
+		assert!(saves.recv_timeout(Duration::from_secs(1)).is_err());
+	}
+
+	#[test]
+	fn keeps_checkpoints_in_a_file() {
+		let path =
+			env::temp_dir().join(format!("firehouse-checkpoints-{}.json", std::process::id()));
+		let path = path.to_str().unwrap();
+
+		let mut store = FileCheckpoints::new(path);
+		assert!(store.load().unwrap().is_empty());
+		store.save("shard-0", "10").unwrap();
+		store.save("shard-1", "20").unwrap();
+		store.save("shard-0", "11").unwrap();
+
+		let checkpoints = FileCheckpoints::new(path).load().unwrap();
+		assert_eq!(Some(&s!("11")), checkpoints.get("shard-0"));
+		assert_eq!(Some(&s!("20")), checkpoints.get("shard-1"));
+
+		fs::remove_file(path).unwrap();
+	}
+}
@@ -0,0 +1,249 @@
+use amiquip::{Channel, Connection};
+use road_postgres::PG;
+use std::{
+	collections::{BTreeMap, HashMap},
+	fmt, io,
+	ops::Deref,
+	sync::{Arc, Mutex},
+	time::Duration,
+};
+
+use crate::checkpoint::CheckpointError;
+use crate::config::Alias;
+use crate::kinesis::{ClientOptions, KinesisError, KinesisHandler};
+use crate::lease::LeaseError;
+
+/// Wait for a stream created by `ensure_stream` to become active at most.
+const ENSURE_TIMEOUT: Duration = Duration::from_secs(60);
+
+#[derive(Debug)]
+pub enum ConnectionError {
+	Alias(String),
+	Redis(redis::RedisError),
+	Amqp(amiquip::Error),
+	Postgres(road_postgres::Error),
+	Checkpoint(CheckpointError),
+	Lease(LeaseError),
+	Kinesis(KinesisError),
+	Io(io::Error),
+}
+
+impl fmt::Display for ConnectionError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			ConnectionError::Alias(e) => write!(f, "{}", e),
+			ConnectionError::Redis(e) => write!(f, "redis: {}", e),
+			ConnectionError::Amqp(e) => write!(f, "amqp: {}", e),
+			ConnectionError::Postgres(e) => write!(f, "postgres: {}", e),
+			ConnectionError::Checkpoint(e) => write!(f, "checkpoint: {}", e),
+			ConnectionError::Lease(e) => write!(f, "lease: {}", e),
+			ConnectionError::Kinesis(e) => write!(f, "kinesis: {}", e),
+			ConnectionError::Io(e) => write!(f, "{}", e),
+		}
+	}
+}
+
+impl From<redis::RedisError> for ConnectionError {
+	fn from(e: redis::RedisError) -> Self {
+		ConnectionError::Redis(e)
+	}
+}
+
+impl From<amiquip::Error> for ConnectionError {
+	fn from(e: amiquip::Error) -> Self {
+		ConnectionError::Amqp(e)
+	}
+}
+
+impl From<road_postgres::Error> for ConnectionError {
+	fn from(e: road_postgres::Error) -> Self {
+		ConnectionError::Postgres(e)
+	}
+}
+
+impl From<CheckpointError> for ConnectionError {
+	fn from(e: CheckpointError) -> Self {
+		ConnectionError::Checkpoint(e)
+	}
+}
+
+impl From<LeaseError> for ConnectionError {
+	fn from(e: LeaseError) -> Self {
+		ConnectionError::Lease(e)
+	}
+}
+
+impl From<KinesisError> for ConnectionError {
+	fn from(e: KinesisError) -> Self {
+		ConnectionError::Kinesis(e)
+	}
+}
+
+impl From<io::Error> for ConnectionError {
+	fn from(e: io::Error) -> Self {
+		ConnectionError::Io(e)
+	}
+}
+
+/// RabbitMQ channel that keeps its connection open for as long as it lives.
+pub struct AmqpChannel {
+	channel: Channel,
+	_connection: Arc<Mutex<Connection>>,
+}
+
+impl Deref for AmqpChannel {
+	type Target = Channel;
+
+	fn deref(&self) -> &Channel {
+		&self.channel
+	}
+}
+
+/// Opens the connections described by the aliases, every input and output
+/// that refers to the same alias shares the underlying client.
+pub struct Connections {
+	aliases: BTreeMap<String, Alias>,
+	kinesis: HashMap<String, KinesisHandler>,
+	redis: HashMap<String, redis::Client>,
+	amqp: HashMap<String, Arc<Mutex<Connection>>>,
+	postgres: HashMap<String, Arc<PG>>,
+}
+
+impl Connections {
+	pub fn new(aliases: BTreeMap<String, Alias>) -> Self {
+		Connections {
+			aliases,
+			kinesis: HashMap::new(),
+			redis: HashMap::new(),
+			amqp: HashMap::new(),
+			postgres: HashMap::new(),
+		}
+	}
+
+	/// Registry for a new set of aliases that reuses the clients of the
+	/// aliases that did not change. The clients of the changed ones are closed
+	/// once the stages using them are dropped.
+	pub fn reconfigure(&self, aliases: BTreeMap<String, Alias>) -> Self {
+		let unchanged = |name: &String| self.aliases.get(name) == aliases.get(name);
+
+		Connections {
+			kinesis: self
+				.kinesis
+				.iter()
+				.filter(|(name, _)| unchanged(name))
+				.map(|(name, c)| (name.clone(), c.clone()))
+				.collect(),
+			redis: self
+				.redis
+				.iter()
+				.filter(|(name, _)| unchanged(name))
+				.map(|(name, c)| (name.clone(), c.clone()))
+				.collect(),
+			amqp: self
+				.amqp
+				.iter()
+				.filter(|(name, _)| unchanged(name))
+				.map(|(name, c)| (name.clone(), c.clone()))
+				.collect(),
+			postgres: self
+				.postgres
+				.iter()
+				.filter(|(name, _)| unchanged(name))
+				.map(|(name, c)| (name.clone(), c.clone()))
+				.collect(),
+			aliases,
+		}
+	}
+
+	fn get(&self, name: &str) -> Result<&Alias, ConnectionError> {
+		self.aliases
+			.get(name)
+			.ok_or_else(|| ConnectionError::Alias(format!("unknown alias {}", name)))
+	}
+
+	fn wrong_kind(name: &str, kind: &str) -> ConnectionError {
+		ConnectionError::Alias(format!("alias {} is not a {} connection", name, kind))
+	}
+
+	pub fn kinesis(&mut self, name: &str) -> Result<KinesisHandler, ConnectionError> {
+		if !self.kinesis.contains_key(name) {
+			let handler = match self.get(name)? {
+				Alias::Kinesis {
+					stream,
+					region,
+					endpoint,
+					ensure_stream,
+					ca_bundle,
+					insecure,
+					credentials,
+				} => {
+					let options = ClientOptions {
+						ca_bundle: ca_bundle.clone(),
+						insecure: *insecure,
+						credentials: credentials.clone(),
+					};
+					let handler = KinesisHandler::new_with_options(
+						stream.clone(),
+						region.as_deref(),
+						endpoint.as_deref(),
+						&options,
+					)?;
+					if *ensure_stream && handler.ensure_stream(1, ENSURE_TIMEOUT)? {
+						println!("Created stream {}", stream);
+					}
+					handler
+				}
+				_ => return Err(Connections::wrong_kind(name, "kinesis")),
+			};
+			self.kinesis.insert(s!(name), handler);
+		}
+
+		Ok(self.kinesis[name].clone())
+	}
+
+	pub fn redis(&mut self, name: &str) -> Result<redis::Connection, ConnectionError> {
+		if !self.redis.contains_key(name) {
+			let client = match self.get(name)? {
+				Alias::Redis { url } => redis::Client::open(url.as_str())?,
+				_ => return Err(Connections::wrong_kind(name, "redis")),
+			};
+			self.redis.insert(s!(name), client);
+		}
+
+		Ok(self.redis[name].get_connection()?)
+	}
+
+	pub fn amqp_channel(&mut self, name: &str) -> Result<AmqpChannel, ConnectionError> {
+		if !self.amqp.contains_key(name) {
+			let connection = match self.get(name)? {
+				Alias::Amqp { url } => Connection::insecure_open(url)?,
+				_ => return Err(Connections::wrong_kind(name, "amqp")),
+			};
+			self.amqp.insert(s!(name), Arc::new(Mutex::new(connection)));
+		}
+
+		let connection = self.amqp[name].clone();
+		let channel = connection
+			.lock()
+			.expect("RabbitMQ connection poisoned")
+			.open_channel(None)?;
+
+		Ok(AmqpChannel {
+			channel,
+			_connection: connection,
+		})
+	}
+
+	/// Connection pool shared by every output writing to the alias.
+	pub fn postgres(&mut self, name: &str) -> Result<Arc<PG>, ConnectionError> {
+		if !self.postgres.contains_key(name) {
+			let pg = match self.get(name)? {
+				Alias::Postgres { dsn } => PG::connect(dsn, vec![])?,
+				_ => return Err(Connections::wrong_kind(name, "postgres")),
+			};
+			self.postgres.insert(s!(name), Arc::new(pg));
+		}
+
+		Ok(self.postgres[name].clone())
+	}
+}
@@ -0,0 +1,208 @@
+use serde_json::Value;
+use std::fmt::Write;
+
+use crate::config::{FirehoseConfig, OutputConfig, OutputKind, TransformAction, TransformConfig};
+use crate::pipeline::PipelineError;
+use crate::router::{get_tenant, get_type, resolve, Resolution};
+use crate::transform::Transforms;
+
+/// Looks up the stored configuration of a tenant.
+type Configs = Box<dyn FnMut(&str) -> redis::RedisResult<Option<String>>>;
+
+fn describe(transform: &TransformConfig) -> String {
+	let mut description = match &transform.action {
+		TransformAction::Rename(r) => format!("rename {} to {}", r.from, r.to),
+		TransformAction::Drop(d) => format!("drop {}", d.field),
+		TransformAction::Set(s) => format!("set {} to {}", s.field, s.value),
+		TransformAction::Move(m) => format!("move {} to {}", m.from, m.to),
+	};
+	if let Some(tenant) = &transform.tenant {
+		description.push_str(&format!(" for tenant {}", tenant));
+	}
+	if let Some(log_type) = &transform.log_type {
+		description.push_str(&format!(" for type {}", log_type));
+	}
+
+	description
+}
+
+/// Tells where records would go through the pipeline of a configuration:
+/// the transforms applied, the outputs selected and, for a router, the
+/// tenant configuration and topic. Nothing is written anywhere, tenants
+/// without configuration are only reported.
+pub struct Explainer {
+	transforms: Transforms,
+	config: FirehoseConfig,
+	configs: Configs,
+}
+
+impl Explainer {
+	/// `configs` looks up tenant configurations for the router output, like
+	/// a GET on its Redis.
+	pub fn new<F>(config: FirehoseConfig, configs: F) -> Self
+	where
+		F: FnMut(&str) -> redis::RedisResult<Option<String>> + 'static,
+	{
+		Explainer {
+			transforms: Transforms::new(&config.transforms),
+			config,
+			configs: Box::new(configs),
+		}
+	}
+
+	/// Report on one record, one line per step.
+	pub fn explain(&mut self, data: &[u8]) -> Result<String, PipelineError> {
+		let mut report = String::new();
+		let mut record: Value = match serde_json::from_slice(data) {
+			Ok(record) => record,
+			Err(e) => {
+				writeln!(
+					report,
+					"  not JSON ({}), no transform nor `when` applies",
+					e
+				)
+				.unwrap();
+				for output in self.config.output.iter() {
+					let outcome = match (&output.when, &output.kind) {
+						(Some(_), _) => "skipped",
+						(None, OutputKind::Router(_)) => "dropped",
+						(None, _) => "written",
+					};
+					writeln!(report, "  output {}: {}", output.name, outcome).unwrap();
+				}
+				return Ok(report);
+			}
+		};
+
+		for i in self.transforms.trace(&mut record) {
+			let transform = describe(&self.config.transforms[i]);
+			writeln!(report, "  transform {}: {}", i + 1, transform).unwrap();
+		}
+		writeln!(
+			report,
+			"  tenant {}, type {}",
+			get_tenant(&record),
+			get_type(&record)
+		)
+		.unwrap();
+
+		for output in self.config.output.clone().iter() {
+			let line = self.output(output, &record)?;
+			writeln!(report, "  output {}: {}", output.name, line).unwrap();
+		}
+
+		Ok(report)
+	}
+
+	fn output(&mut self, output: &OutputConfig, record: &Value) -> Result<String, PipelineError> {
+		if let Some(when) = &output.when {
+			if !when.matches(record) {
+				return Ok(format!("skipped, `{}` does not match", when.as_str()));
+			}
+		}
+
+		let router = match &output.kind {
+			OutputKind::Router(router) => router,
+			_ => return Ok(s!("written")),
+		};
+
+		let configs = &mut self.configs;
+		let line = match resolve(&router.routes, |tenant| configs(tenant), record)? {
+			Resolution::Route(i, Some(topic)) => format!(
+				"route {} `{}`, published to {}",
+				i + 1,
+				router.routes[i].when.as_str(),
+				topic
+			),
+			Resolution::Route(i, None) => {
+				format!(
+					"route {} `{}`, dropped",
+					i + 1,
+					router.routes[i].when.as_str()
+				)
+			}
+			Resolution::Tenant { config, topic } => {
+				format!("config {}, published to {}", config, topic)
+			}
+			Resolution::InvalidConfig(e) => format!("invalid config ({}), dropped", e),
+			Resolution::Unknown => s!("no config, one would be created and the record dropped"),
+		};
+
+		Ok(line)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::Explainer;
+	use crate::config::validate;
+
+	const CONFIG: &str = "
+aliases:
+  cache: { redis: { url: 'redis://localhost' } }
+  rabbit: { amqp: { url: 'amqp://localhost' } }
+input: []
+transforms:
+  - rename: { from: metadata.customer, to: tenant }
+output:
+  - name: errors
+    when: level >= 40
+    stdout: {}
+  - name: router
+    router:
+      redis: cache
+      amqp: rabbit
+      routes:
+        - when: metadata.type == \"audit\"
+          topic: audit.{tenant}
+        - when: metadata.type == \"debug\"
+          drop: true
+";
+
+	fn explainer() -> Explainer {
+		Explainer::new(validate(CONFIG).unwrap(), |tenant| {
+			Ok(match tenant {
+				"\"acme\"" => Some(s!(r#"{"sink": "elastic"}"#)),
+				"\"broken\"" => Some(s!("{")),
+				_ => None,
+			})
+		})
+	}
+
+	#[test]
+	fn explains_routes_and_tenant_configs() {
+		let mut explainer = explainer();
+		let mut explain = |record: &str| explainer.explain(record.as_bytes()).unwrap();
+
+		assert_eq!(
+			"  transform 1: rename metadata.customer to tenant
+  tenant \"acme\", type \"audit\"
+  output errors: skipped, `level >= 40` does not match
+  output router: route 1 `metadata.type == \"audit\"`, published to audit.acme
+",
+			explain(r#"{"metadata": {"customer": "acme", "type": "audit"}}"#)
+		);
+		assert_eq!(
+			"  tenant \"acme\", type \"nginx\"
+  output errors: written
+  output router: config {\"sink\":\"elastic\"}, published to all.\"elastic\".\"nginx\".\"acme\"
+",
+			explain(r#"{"metadata": {"tenant": "acme", "type": "nginx"}, "level": 50}"#)
+		);
+		assert!(
+			explain(r#"{"metadata": {"tenant": "acme", "type": "debug"}}"#)
+				.ends_with("route 2 `metadata.type == \"debug\"`, dropped\n")
+		);
+		assert!(explain(r#"{"metadata": {"tenant": "broken"}}"#)
+			.contains("output router: invalid config ("));
+		assert!(explain(r#"{"metadata": {"tenant": "new"}}"#)
+			.ends_with("no config, one would be created and the record dropped\n"));
+	}
+
+	#[test]
+	fn explains_records_that_are_not_json() {
+		let report = explainer().explain(b"GET /").unwrap();
+
+		assert!(report.ends_with("output errors: skipped\n  output router: dropped\n"));
+	}
+}
@@ -0,0 +1,346 @@
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::Value;
+use std::{cmp::Ordering, fmt};
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+	Path(Vec<String>),
+	Literal(Value),
+	Op(&'static str),
+	Open,
+	Close,
+}
+
+const OPS: &[&str] = &["==", "!=", "<=", ">=", "&&", "||", "<", ">", "!"];
+
+fn tokenize(text: &str) -> Result<Vec<(usize, Token)>, String> {
+	let chars: Vec<char> = text.chars().collect();
+	let mut tokens = Vec::new();
+	let mut i = 0;
+
+	while i < chars.len() {
+		let start = i;
+		let c = chars[i];
+
+		if c.is_whitespace() {
+			i += 1;
+			continue;
+		}
+
+		let token = if c == '(' {
+			i += 1;
+			Token::Open
+		} else if c == ')' {
+			i += 1;
+			Token::Close
+		} else if c == '"' {
+			let mut literal = String::new();
+			i += 1;
+			loop {
+				match chars.get(i) {
+					None => return Err(format!("unclosed string at column {}", start + 1)),
+					Some('"') => break,
+					Some('\\') if i + 1 < chars.len() => {
+						literal.push(chars[i + 1]);
+						i += 2;
+					}
+					Some(c) => {
+						literal.push(*c);
+						i += 1;
+					}
+				}
+			}
+			i += 1;
+			Token::Literal(Value::String(literal))
+		} else if c.is_ascii_digit()
+			|| (c == '-' && chars.get(i + 1).is_some_and(char::is_ascii_digit))
+		{
+			i += 1;
+			while i < chars.len() && (chars[i].is_ascii_digit() || chars[i] == '.') {
+				i += 1;
+			}
+			let number: String = chars[start..i].iter().collect();
+			match number.parse::<f64>() {
+				Ok(n) => Token::Literal(n.into()),
+				Err(_) => {
+					return Err(format!(
+						"invalid number `{}` at column {}",
+						number,
+						start + 1
+					))
+				}
+			}
+		} else if c.is_alphabetic() || c == '_' {
+			while i < chars.len()
+				&& (chars[i].is_alphanumeric()
+					|| chars[i] == '_'
+					|| chars[i] == '-'
+					|| chars[i] == '.')
+			{
+				i += 1;
+			}
+			let word: String = chars[start..i].iter().collect();
+			match word.as_str() {
+				"true" => Token::Literal(Value::Bool(true)),
+				"false" => Token::Literal(Value::Bool(false)),
+				"null" => Token::Literal(Value::Null),
+				_ if word.split('.').any(str::is_empty) => {
+					return Err(format!("invalid field `{}` at column {}", word, start + 1))
+				}
+				_ => Token::Path(word.split('.').map(String::from).collect()),
+			}
+		} else {
+			let rest: String = chars[i..].iter().take(2).collect();
+			match OPS.iter().find(|op| rest.starts_with(*op)) {
+				Some(op) => {
+					i += op.len();
+					Token::Op(op)
+				}
+				None => return Err(format!("unexpected `{}` at column {}", c, start + 1)),
+			}
+		};
+
+		tokens.push((start, token));
+	}
+
+	Ok(tokens)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Expr {
+	Path(Vec<String>),
+	Literal(Value),
+	Not(Box<Expr>),
+	And(Box<Expr>, Box<Expr>),
+	Or(Box<Expr>, Box<Expr>),
+	Compare(&'static str, Box<Expr>, Box<Expr>),
+}
+
+struct Parser {
+	tokens: Vec<(usize, Token)>,
+	position: usize,
+	end: usize,
+}
+
+impl Parser {
+	fn peek(&self) -> Option<&Token> {
+		self.tokens.get(self.position).map(|(_, t)| t)
+	}
+
+	fn column(&self) -> usize {
+		self.tokens.get(self.position).map_or(self.end, |(c, _)| *c) + 1
+	}
+
+	fn next_op(&mut self, ops: &[&str]) -> Option<&'static str> {
+		match self.peek() {
+			Some(Token::Op(op)) if ops.contains(op) => {
+				let op = *op;
+				self.position += 1;
+				Some(op)
+			}
+			_ => None,
+		}
+	}
+
+	fn or(&mut self) -> Result<Expr, String> {
+		let mut left = self.and()?;
+		while self.next_op(&["||"]).is_some() {
+			left = Expr::Or(Box::new(left), Box::new(self.and()?));
+		}
+		Ok(left)
+	}
+
+	fn and(&mut self) -> Result<Expr, String> {
+		let mut left = self.not()?;
+		while self.next_op(&["&&"]).is_some() {
+			left = Expr::And(Box::new(left), Box::new(self.not()?));
+		}
+		Ok(left)
+	}
+
+	fn not(&mut self) -> Result<Expr, String> {
+		if self.next_op(&["!"]).is_some() {
+			return Ok(Expr::Not(Box::new(self.not()?)));
+		}
+		self.compare()
+	}
+
+	fn compare(&mut self) -> Result<Expr, String> {
+		let left = self.primary()?;
+		match self.next_op(&["==", "!=", "<", "<=", ">", ">="]) {
+			Some(op) => Ok(Expr::Compare(op, Box::new(left), Box::new(self.primary()?))),
+			None => Ok(left),
+		}
+	}
+
+	fn primary(&mut self) -> Result<Expr, String> {
+		let column = self.column();
+		let token = self.peek().cloned();
+		self.position += 1;
+
+		match token {
+			Some(Token::Path(path)) => Ok(Expr::Path(path)),
+			Some(Token::Literal(value)) => Ok(Expr::Literal(value)),
+			Some(Token::Open) => {
+				let expr = self.or()?;
+				match self.peek() {
+					Some(Token::Close) => {
+						self.position += 1;
+						Ok(expr)
+					}
+					_ => Err(format!("expected `)` at column {}", self.column())),
+				}
+			}
+			Some(_) => Err(format!("expected a field or a value at column {}", column)),
+			None => Err(s!("unexpected end of expression")),
+		}
+	}
+}
+
+fn resolve<'a>(expr: &'a Expr, record: &'a Value) -> Option<&'a Value> {
+	match expr {
+		Expr::Path(path) => path.iter().try_fold(record, |v, key| v.get(key)),
+		Expr::Literal(value) => Some(value),
+		_ => None,
+	}
+}
+
+/// Missing fields compare as `null`.
+fn compare(op: &str, left: Option<&Value>, right: Option<&Value>) -> bool {
+	let null = Value::Null;
+	let ordering = match (left.unwrap_or(&null), right.unwrap_or(&null)) {
+		(Value::Number(l), Value::Number(r)) => l
+			.as_f64()
+			.and_then(|l| r.as_f64().and_then(|r| l.partial_cmp(&r))),
+		(Value::String(l), Value::String(r)) => Some(l.cmp(r)),
+		(l, r) if l == r => Some(Ordering::Equal),
+		_ => None,
+	};
+
+	match op {
+		"==" => ordering == Some(Ordering::Equal),
+		"!=" => ordering != Some(Ordering::Equal),
+		"<" => ordering == Some(Ordering::Less),
+		"<=" => ordering == Some(Ordering::Less) || ordering == Some(Ordering::Equal),
+		">" => ordering == Some(Ordering::Greater),
+		">=" => ordering == Some(Ordering::Greater) || ordering == Some(Ordering::Equal),
+		_ => false,
+	}
+}
+
+fn eval(expr: &Expr, record: &Value) -> bool {
+	match expr {
+		Expr::Not(e) => !eval(e, record),
+		Expr::And(l, r) => eval(l, record) && eval(r, record),
+		Expr::Or(l, r) => eval(l, record) || eval(r, record),
+		Expr::Compare(op, l, r) => compare(op, resolve(l, record), resolve(r, record)),
+		// A lone field or value is true unless missing, null or false
+		value => match resolve(value, record) {
+			None | Some(Value::Null) | Some(Value::Bool(false)) => false,
+			Some(_) => true,
+		},
+	}
+}
+
+/// Condition on JSON records like `metadata.type == "error" && level >= 40`.
+///
+/// Fields are dotted paths, values are strings, numbers, `true`, `false` or
+/// `null`. Comparisons are `==`, `!=`, `<`, `<=`, `>` and `>=`, combined with
+/// `&&`, `||`, `!` and parentheses. A field on its own is true when it is
+/// present and neither `null` nor `false`. The expression is parsed once,
+/// matching a record only walks the tree.
+#[derive(Clone)]
+pub struct Filter {
+	source: String,
+	expr: Expr,
+}
+
+impl Filter {
+	pub fn parse(source: &str) -> Result<Self, String> {
+		let mut parser = Parser {
+			tokens: tokenize(source)?,
+			position: 0,
+			end: source.chars().count(),
+		};
+		let expr = parser.or()?;
+		if parser.peek().is_some() {
+			return Err(format!("unexpected token at column {}", parser.column()));
+		}
+
+		Ok(Filter {
+			source: s!(source),
+			expr,
+		})
+	}
+
+	pub fn matches(&self, record: &Value) -> bool {
+		eval(&self.expr, record)
+	}
+
+	pub fn as_str(&self) -> &str {
+		&self.source
+	}
+}
+
+impl fmt::Debug for Filter {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "Filter({:?})", self.source)
+	}
+}
+
+impl PartialEq for Filter {
+	fn eq(&self, other: &Filter) -> bool {
+		self.source == other.source
+	}
+}
+
+impl Serialize for Filter {
+	fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+		serializer.serialize_str(&self.source)
+	}
+}
+
+impl<'de> Deserialize<'de> for Filter {
+	fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+		let source = String::deserialize(deserializer)?;
+		Filter::parse(&source)
+			.map_err(|e| de::Error::custom(format!("invalid expression `{}`: {}", source, e)))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::Filter;
+	use serde_json::json;
+
+	#[test]
+	fn evaluates_expressions() {
+		let record = json!({
+			"metadata": { "type": "error", "tenant": "acme" },
+			"level": 50,
+			"debug": false,
+		});
+		let matches = |e: &str| Filter::parse(e).unwrap().matches(&record);
+
+		assert!(matches(r#"metadata.type == "error" && level >= 40"#));
+		assert!(!matches(r#"metadata.type == "error" && level < 40"#));
+		assert!(matches(r#"metadata.tenant != "other" || missing"#));
+		assert!(matches("!(debug || missing) && metadata"));
+		assert!(matches("level > -1.5 && missing == null"));
+		assert!(!matches(r#"level == "50""#));
+	}
+
+	#[test]
+	fn reports_syntax_errors() {
+		let error = |e: &str| Filter::parse(e).unwrap_err();
+
+		assert_eq!(
+			"expected a field or a value at column 10",
+			error("level >= && debug")
+		);
+		assert_eq!("unexpected token at column 7", error("level 40"));
+		assert_eq!("expected `)` at column 13", error("(level == 40"));
+		assert_eq!("unclosed string at column 10", error("level == \"40"));
+		assert_eq!("unexpected end of expression", error("level =="));
+	}
+}
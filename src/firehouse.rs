@@ -1,36 +1,737 @@
-use serde_yaml::Value;
-use std::fs::File;
+use crossbeam::channel::{bounded, select, unbounded, Receiver, RecvTimeoutError, Sender};
+use serde_json::Value;
+use signal_hook::SIGTERM;
+use std::{
+	collections::HashMap,
+	fs,
+	sync::{
+		atomic::{AtomicBool, Ordering},
+		Arc,
+	},
+	thread::{self, JoinHandle},
+	time::Duration,
+};
 
-use crate::kinesis::KinesisHandler;
+use crate::checkpoint::{CheckpointStore, FileCheckpoints, PostgresCheckpoints, RedisCheckpoints};
+use crate::config::{
+	self, CheckpointConfig, ConfigDiff, ConfigError, FirehoseConfig, FullPolicy, InputConfig,
+	InputKind, LeaseConfig, OutputConfig, OutputKind,
+};
+use crate::connections::{ConnectionError, Connections};
+use crate::kinesis::Polling;
+use crate::lease::{LeaseStore, Leases, PostgresLeases, RedisLeases};
+use crate::pipeline::{
+	self, Ack, AmqpSink, AmqpSource, BufferSender, Delivery, FileSink, HttpSink, KinesisSink,
+	KinesisSource, Message, PostgresSink, RedisSink, RedisSource, Sink, Source, StdoutSink,
+};
+use crate::reload;
+use crate::router::Router;
+use crate::transform::Transforms;
+
+fn open_source(
+	input: &InputConfig,
+	connections: &mut Connections,
+) -> Result<Box<dyn Source>, ConnectionError> {
+	Ok(match &input.kind {
+		InputKind::Kinesis(k) => {
+			let mut handler = connections.kinesis(&k.alias)?.with_polling(Polling {
+				limit: k.limit,
+				..Polling::default()
+			});
+			if let Some(capacity) = k.capacity {
+				handler = handler.with_capacity(capacity);
+			}
+			let store: Box<dyn CheckpointStore> = match &k.checkpoint {
+				None => return Ok(Box::new(KinesisSource::new(handler, k.start.clone())?)),
+				Some(CheckpointConfig::Redis(alias)) => Box::new(RedisCheckpoints::new(
+					connections.redis(alias)?,
+					&input.name,
+				)),
+				Some(CheckpointConfig::Postgres(alias)) => Box::new(PostgresCheckpoints::new(
+					connections.postgres(alias)?,
+					&input.name,
+				)?),
+				Some(CheckpointConfig::File(path)) => Box::new(FileCheckpoints::new(path)),
+			};
+			let leases: Option<Box<dyn LeaseStore>> = match &k.lease {
+				None => None,
+				Some(LeaseConfig::Redis(alias)) => Some(Box::new(RedisLeases::new(
+					connections.redis(alias)?,
+					&input.name,
+				))),
+				Some(LeaseConfig::Postgres(alias)) => Some(Box::new(PostgresLeases::new(
+					connections.postgres(alias)?,
+					&input.name,
+				)?)),
+			};
+			Box::new(KinesisSource::resume(
+				handler,
+				&input.name,
+				k.start.clone(),
+				store,
+				leases.map(Leases::new),
+			)?)
+		}
+		InputKind::Amqp(a) => Box::new(AmqpSource::new(
+			connections.amqp_channel(&a.alias)?,
+			&a.queue,
+		)?),
+		InputKind::Redis(r) => {
+			Box::new(RedisSource::new(connections.redis(&r.alias)?, &r.channel)?)
+		}
+	})
+}
+
+fn open_sink(
+	output: &OutputConfig,
+	connections: &mut Connections,
+) -> Result<Box<dyn Sink>, ConnectionError> {
+	Ok(match &output.kind {
+		OutputKind::Stdout(_) => Box::new(StdoutSink),
+		OutputKind::File(f) => Box::new(FileSink::open(&f.path)?),
+		OutputKind::Router(r) => Box::new(Router::new(
+			connections.redis(&r.redis)?,
+			connections.amqp_channel(&r.amqp)?,
+			r.routes.clone(),
+		)),
+		OutputKind::Amqp(a) => Box::new(AmqpSink::new(
+			connections.amqp_channel(&a.alias)?,
+			&a.exchange,
+			a.routing_key.as_deref(),
+		)),
+		OutputKind::Redis(r) => Box::new(RedisSink::new(connections.redis(&r.alias)?, &r.channel)),
+		OutputKind::Http(h) => Box::new(HttpSink::new(&h.url)),
+		OutputKind::Postgres(p) => Box::new(PostgresSink::new(
+			connections.postgres(&p.alias)?,
+			&p.table,
+			p.fields.clone(),
+		)),
+		OutputKind::Kinesis(k) => Box::new(KinesisSink::new(
+			connections.kinesis(&k.alias)?,
+			k.partition_key.clone(),
+		)),
+	})
+}
+
+/// Thread forwarding the messages of one input to the dispatcher, it hands
+/// the source back once stopped.
+struct InputStage {
+	stop: Arc<AtomicBool>,
+	thread: JoinHandle<Box<dyn Source>>,
+}
+
+impl InputStage {
+	fn start(name: String, mut source: Box<dyn Source>, records: Sender<Delivery>) -> Self {
+		let stop = Arc::new(AtomicBool::new(false));
+		let flag = stop.clone();
+
+		let thread = thread::spawn(move || {
+			while !flag.load(Ordering::SeqCst) {
+				match source.recv(Duration::from_millis(500)) {
+					Ok(Some(delivery)) => {
+						if records.send(delivery).is_err() {
+							return source;
+						}
+					}
+					Ok(None) => {}
+					Err(e) => {
+						eprintln!("Input {} stopped: {}", name, e);
+						return source;
+					}
+				}
+			}
+
+			// Messages already fetched by the source are not lost
+			for delivery in source.stop() {
+				if records.send(delivery).is_err() {
+					return source;
+				}
+			}
+			while let Ok(Some(delivery)) = source.recv(Duration::from_millis(0)) {
+				if records.send(delivery).is_err() {
+					return source;
+				}
+			}
+			source
+		});
+
+		InputStage { stop, thread }
+	}
+
+	/// Stops the input, `dispatch` takes what it still forwards meanwhile.
+	fn stop<D: FnMut(Delivery)>(
+		self,
+		records: &Receiver<Delivery>,
+		mut dispatch: D,
+	) -> Box<dyn Source> {
+		self.stop.store(true, Ordering::SeqCst);
+		while !self.thread.is_finished() {
+			if let Ok(delivery) = records.recv_timeout(Duration::from_millis(10)) {
+				dispatch(delivery);
+			}
+		}
+		self.thread.join().expect("Input thread panicked")
+	}
+}
+
+/// Thread writing the messages queued for one output in batches of up to
+/// `batch`, a partial batch is written once the queue is idle for a second.
+/// Each output has its own buffer of `buffer` messages.
+struct OutputStage {
+	sender: BufferSender,
+	thread: JoinHandle<()>,
+}
+
+impl OutputStage {
+	fn start(output: &OutputConfig, mut sink: Box<dyn Sink>) -> Self {
+		let name = output.name.clone();
+		let batch = output.batch;
+		let (sender, r) =
+			pipeline::buffer(&name, output.buffer, &output.full).unwrap_or_else(|e| {
+				eprintln!("Output {} cannot spill, it will block instead: {}", name, e);
+				pipeline::buffer(&name, output.buffer, &FullPolicy::Block)
+					.expect("Buffers without spill cannot fail")
+			});
+
+		let thread = thread::spawn(move || {
+			let mut messages = Vec::new();
+			let mut acks = Vec::new();
+			let mut write = |messages: &mut Vec<Message>, acks: &mut Vec<Ack>| {
+				if messages.is_empty() {
+					return;
+				}
+				// Acks dropped without `ack` tell the source the write failed
+				match sink.write(messages) {
+					Ok(()) => acks.drain(..).for_each(Ack::ack),
+					Err(e) => {
+						eprintln!(
+							"Output {} cannot write {} records: {}",
+							name,
+							messages.len(),
+							e
+						)
+					}
+				}
+				messages.clear();
+				acks.clear();
+			};
+
+			loop {
+				match r.recv_timeout(Duration::from_secs(1)) {
+					Ok(Delivery { message, ack }) => {
+						messages.push(message);
+						acks.push(ack);
+						if messages.len() >= batch {
+							write(&mut messages, &mut acks);
+						}
+					}
+					Err(RecvTimeoutError::Timeout) => write(&mut messages, &mut acks),
+					Err(RecvTimeoutError::Disconnected) => break,
+				}
+			}
+			write(&mut messages, &mut acks);
+
+			if let Err(e) = sink.close() {
+				eprintln!("Output {} did not close cleanly: {}", name, e);
+			}
+		});
+
+		OutputStage { sender, thread }
+	}
+
+	/// Waits until every queued message has been written.
+	fn stop(self) {
+		drop(self.sender);
+		self.thread.join().expect("Output thread panicked");
+	}
+}
+
+/// Inputs and outputs opened but not running yet.
+struct Prepared {
+	inputs: Vec<(String, Box<dyn Source>)>,
+	outputs: Vec<(OutputConfig, Box<dyn Sink>)>,
+}
+
+impl Prepared {
+	/// Opens the inputs and outputs of `config` named in `inputs` and
+	/// `outputs`, fails if any of them cannot be opened.
+	fn open(
+		config: &FirehoseConfig,
+		connections: &mut Connections,
+		inputs: &[String],
+		outputs: &[String],
+	) -> Result<Self, ConnectionError> {
+		let mut prepared = Prepared {
+			inputs: Vec::new(),
+			outputs: Vec::new(),
+		};
+
+		for output in config.output.iter().filter(|o| outputs.contains(&o.name)) {
+			let sink = open_sink(output, connections)?;
+			// The spill file itself is opened once the previous stage is gone
+			if let FullPolicy::Spill(dir) = &output.full {
+				fs::create_dir_all(dir)?;
+			}
+			prepared.outputs.push((output.clone(), sink));
+		}
+
+		for input in config.input.iter().filter(|i| inputs.contains(&i.name)) {
+			let source = open_source(input, connections)?;
+			prepared.inputs.push((input.name.clone(), source));
+		}
+
+		Ok(prepared)
+	}
+
+	/// Opens each of `inputs` on its own, those that cannot be opened stay
+	/// stopped.
+	fn reopen(config: &FirehoseConfig, connections: &mut Connections, inputs: &[String]) -> Self {
+		let mut prepared = Prepared {
+			inputs: Vec::new(),
+			outputs: Vec::new(),
+		};
+
+		for name in inputs {
+			match Prepared::open(config, connections, std::slice::from_ref(name), &[]) {
+				Ok(opened) => prepared.inputs.extend(opened.inputs),
+				Err(e) => eprintln!("Input {} stays stopped, it cannot be opened: {}", name, e),
+			}
+		}
+
+		prepared
+	}
+}
+
+/// What a new configuration adds to the running pipeline, opened without
+/// touching it.
+struct Reload {
+	config: FirehoseConfig,
+	connections: Connections,
+	diff: ConfigDiff,
+	prepared: Prepared,
+}
+
+impl Reload {
+	/// Opens the outputs and the inputs `config` adds to `current`, those it
+	/// changes are opened again only once the running ones are closed.
+	/// `connections` are those of `config`.
+	fn open(
+		current: &FirehoseConfig,
+		mut connections: Connections,
+		config: FirehoseConfig,
+	) -> Result<Self, ConnectionError> {
+		let diff = current.diff(&config);
+		let new: Vec<String> = diff
+			.inputs
+			.added
+			.iter()
+			.filter(|name| !diff.inputs.removed.contains(name))
+			.cloned()
+			.collect();
+		let prepared = Prepared::open(&config, &mut connections, &new, &diff.outputs.added)?;
+
+		Ok(Reload {
+			config,
+			connections,
+			diff,
+			prepared,
+		})
+	}
+}
+
+/// Steps of a reload run off the dispatch thread.
+enum Reloading {
+	/// What the new configuration adds was opened, or cannot be.
+	Opened(Result<Box<Reload>, ConnectionError>),
+	/// The inputs it changed were opened again, those that could be.
+	Reopened(Prepared),
+}
 
 pub struct Firehose {
-    config: FirehoseConfig,
+	config: FirehoseConfig,
+	connections: Connections,
+	transforms: Transforms,
+	path: Option<String>,
+	/// Bounded so inputs stop reading once outputs that block are full, it
+	/// keeps the size of the first configuration across reloads.
+	records: (Sender<Delivery>, Receiver<Delivery>),
+	inputs: HashMap<String, InputStage>,
+	outputs: HashMap<String, OutputStage>,
 }
 
-#[derive(Serialize, Deserialize)]
-pub struct FirehoseConfig {
-    input: Value,
-    output: Value,
-    aliases: Value,
+impl Firehose {
+	/// The pipeline is reloaded whenever the file changes or the process
+	/// receives a SIGHUP.
+	pub fn new(path: &str) -> Result<Self, Vec<ConfigError>> {
+		let mut firehose = Firehose::from_config(FirehoseConfig::load(path)?);
+		firehose.path = Some(s!(path));
+
+		Ok(firehose)
+	}
+
+	pub fn from_yaml(config: &str) -> Result<Self, Vec<ConfigError>> {
+		Ok(Firehose::from_config(config::validate(config)?))
+	}
+
+	pub fn from_config(config: FirehoseConfig) -> Self {
+		Firehose {
+			connections: Connections::new(config.aliases.clone()),
+			transforms: Transforms::new(&config.transforms),
+			records: bounded(config.read_ahead()),
+			config,
+			path: None,
+			inputs: HashMap::new(),
+			outputs: HashMap::new(),
+		}
+	}
+
+	fn start(&mut self, prepared: Prepared) {
+		for (output, sink) in prepared.outputs {
+			println!("Starting output {}", output.name);
+			let stage = OutputStage::start(&output, sink);
+			self.outputs.insert(output.name, stage);
+		}
+
+		for (name, source) in prepared.inputs {
+			println!("Starting input {}", name);
+			let stage = InputStage::start(name.clone(), source, self.records.0.clone());
+			self.inputs.insert(name, stage);
+		}
+	}
+
+	/// Builds every input and output described in the configuration.
+	pub fn init(&mut self) -> Result<(), ConnectionError> {
+		let inputs: Vec<String> = self.config.input.iter().map(|i| i.name.clone()).collect();
+		let outputs: Vec<String> = self.config.output.iter().map(|o| o.name.clone()).collect();
+		let prepared = Prepared::open(&self.config, &mut self.connections, &inputs, &outputs)?;
+
+		self.start(prepared);
+
+		Ok(())
+	}
+
+	/// Stops an input, what it forwards meanwhile is dispatched so it never
+	/// waits for room in the channel.
+	fn stop_input(&mut self, name: &str) -> Option<Box<dyn Source>> {
+		let stage = self.inputs.remove(name)?;
+		println!("Stopping input {}", name);
+		let records = self.records.1.clone();
+
+		Some(stage.stop(&records, |delivery| self.dispatch(delivery)))
+	}
+
+	/// Moves the running pipeline to `config`, opening what it needs on the
+	/// calling thread, see `apply`. If a new output or a new input cannot be
+	/// opened the old pipeline is kept.
+	pub fn reload(&mut self, config: FirehoseConfig) -> Result<(), ConnectionError> {
+		let connections = self.connections.reconfigure(config.aliases.clone());
+		let reload = Reload::open(&self.config, connections, config)?;
+		let changed = self.apply(reload);
+		let prepared = Prepared::reopen(&self.config, &mut self.connections, &changed);
+		self.start(prepared);
+
+		Ok(())
+	}
+
+	/// Opens what `config` adds to the running pipeline from a thread of its
+	/// own, `reloading` hears once it is done.
+	fn open_reload(&self, config: FirehoseConfig, reloading: &Sender<Reloading>) {
+		let current = self.config.clone();
+		let connections = self.connections.reconfigure(config.aliases.clone());
+		let reloading = reloading.clone();
+
+		thread::spawn(move || {
+			let opened = Reload::open(&current, connections, config).map(Box::new);
+			let _ = reloading.send(Reloading::Opened(opened));
+		});
+	}
+
+	/// Opens the inputs `apply` stopped again from a thread of its own,
+	/// `reloading` hears once it is done.
+	fn reopen(&self, changed: Vec<String>, reloading: &Sender<Reloading>) {
+		let config = self.config.clone();
+		let mut connections = self.connections.reconfigure(config.aliases.clone());
+		let reloading = reloading.clone();
+
+		thread::spawn(move || {
+			let prepared = Prepared::reopen(&config, &mut connections, &changed);
+			let _ = reloading.send(Reloading::Reopened(prepared));
+		});
+	}
+
+	/// Moves the running pipeline to the configuration of `reload`, whose
+	/// new inputs and outputs are already open. Unchanged inputs and outputs
+	/// keep running and removed outputs deliver what they have queued before
+	/// stopping. Removed and changed inputs are stopped and closed, saving
+	/// the checkpoints of what was acked so far, before their replacements
+	/// read anything: the changed ones are returned, to be opened again.
+	fn apply(&mut self, reload: Reload) -> Vec<String> {
+		let Reload {
+			config,
+			connections,
+			diff,
+			prepared,
+		} = reload;
+
+		let mut sources = Vec::new();
+		for name in diff.inputs.removed.iter() {
+			if let Some(source) = self.stop_input(name) {
+				sources.push((name, source));
+			}
+		}
+
+		// What the stopped inputs forwarded still goes to the old outputs
+		for delivery in self.records.1.clone().try_iter() {
+			self.dispatch(delivery);
+		}
+
+		for name in diff.outputs.removed.iter() {
+			println!("Draining output {}", name);
+			if let Some(stage) = self.outputs.remove(name) {
+				stage.stop();
+			}
+		}
+
+		for (name, mut source) in sources {
+			if let Err(e) = source.close() {
+				eprintln!("Input {} did not close cleanly: {}", name, e);
+			}
+		}
+
+		self.transforms = Transforms::new(&config.transforms);
+		self.config = config;
+		self.connections = connections;
+		self.start(prepared);
+
+		let removed = diff.inputs.removed;
+		diff.inputs
+			.added
+			.into_iter()
+			.filter(|name| removed.contains(name))
+			.collect()
+	}
+
+	/// Applies the transforms and returns the resulting record when it is
+	/// needed by the `when` of an output. Records that are not JSON pass
+	/// through untouched.
+	fn transform(&self, message: &mut Message) -> Option<Value> {
+		let filtered = self.config.output.iter().any(|o| o.when.is_some());
+		if self.transforms.is_empty() && !filtered {
+			return None;
+		}
+
+		let mut record = message.json().ok()?;
+		if !self.transforms.is_empty() {
+			self.transforms.apply(&mut record);
+			message.data = serde_json::to_vec(&record)
+				.expect("JSON values always serialize")
+				.into();
+		}
+
+		Some(record)
+	}
+
+	/// Queues a copy of the message for every output whose `when` matches,
+	/// the source hears back once all of them wrote it.
+	fn dispatch(&mut self, mut delivery: Delivery) {
+		let record = self.transform(&mut delivery.message);
+
+		for output in self.config.output.iter() {
+			let selected = match (&output.when, &record) {
+				(None, _) => true,
+				(Some(when), Some(record)) => when.matches(record),
+				(Some(_), None) => false,
+			};
+			if !selected {
+				continue;
+			}
+
+			if let Some(stage) = self.outputs.get_mut(&output.name) {
+				let copy = Delivery::new(delivery.message.clone(), delivery.ack.clone());
+				stage.sender.send(copy);
+			}
+		}
+		delivery.ack.ack();
+	}
+
+	/// Stops the inputs, delivers what they already fetched, waits for the
+	/// outputs to write everything queued and then closes the inputs so
+	/// their checkpoints include it before their leases are given up.
+	pub fn shutdown(mut self) {
+		let mut sources = Vec::new();
+		let names: Vec<String> = self.inputs.keys().cloned().collect();
+		for name in names {
+			if let Some(source) = self.stop_input(&name) {
+				sources.push((name, source));
+			}
+		}
+
+		for delivery in self.records.1.clone().try_iter() {
+			self.dispatch(delivery);
+		}
+
+		for (name, stage) in self.outputs.drain() {
+			println!("Draining output {}", name);
+			stage.stop();
+		}
+
+		for (name, mut source) in sources {
+			if let Err(e) = source.close() {
+				eprintln!("Input {} did not close cleanly: {}", name, e);
+			}
+		}
+	}
+
+	/// Delivers every record coming from the inputs to all the outputs until
+	/// the process gets a SIGTERM, then shuts the pipeline down.
+	pub fn run(mut self) {
+		let term = Arc::new(AtomicBool::new(false));
+		signal_hook::flag::register(SIGTERM, term.clone()).expect("Cannot listen to SIGTERM");
+
+		let (reloads, reload_receiver) = unbounded();
+		if let Some(path) = self.path.clone() {
+			reload::watch(path, reloads);
+		}
+
+		self.init().expect("Cannot start the pipeline");
+		let records = self.records.1.clone();
+		// Reloads are opened one at a time, off this thread so records keep
+		// flowing meanwhile. Only the latest configuration waits its turn.
+		let (reloading, steps) = unbounded();
+		let mut busy = false;
+		let mut waiting: Option<FirehoseConfig> = None;
+
+		while !term.load(Ordering::SeqCst) {
+			select! {
+				recv(records) -> delivery => self.dispatch(delivery.expect("Inputs closed")),
+				recv(reload_receiver) -> config => {
+					let config = config.expect("Reload watcher stopped");
+					if busy {
+						waiting = Some(config);
+					} else {
+						busy = true;
+						self.open_reload(config, &reloading);
+					}
+				}
+				recv(steps) -> step => {
+					let done = match step.expect("Reload thread stopped") {
+						Reloading::Opened(Ok(reload)) => {
+							let changed = self.apply(*reload);
+							self.reopen(changed, &reloading);
+							false
+						}
+						Reloading::Opened(Err(e)) => {
+							eprintln!("Rejecting the new configuration, {}", e);
+							true
+						}
+						Reloading::Reopened(prepared) => {
+							self.start(prepared);
+							println!("Configuration reloaded");
+							true
+						}
+					};
+					if done {
+						busy = false;
+						if let Some(config) = waiting.take() {
+							busy = true;
+							self.open_reload(config, &reloading);
+						}
+					}
+				}
+				default(Duration::from_millis(500)) => {}
+			}
+		}
+
+		println!("Shutting down");
+		self.shutdown();
+	}
 }
 
-// impl Firehose {
-//     fn new(config: String) -> Self {
-//         let config =
-//             serde_yaml::from_str(config.as_str()).expect("We couldn't parse this YAML file");
-//         Firehose { config }
-//     }
+#[cfg(test)]
+mod tests {
+	use super::{Firehose, InputStage, OutputStage};
+	use crate::pipeline::{Ack, Delivery, Message, PipelineError, Sink, Source};
+	use crossbeam::channel::{unbounded, Receiver};
+	use std::{
+		sync::{
+			atomic::{AtomicUsize, Ordering},
+			Arc,
+		},
+		thread,
+		time::Duration,
+	};
+
+	/// Gives as many records as asked, counting them.
+	struct Endless(Arc<AtomicUsize>);
+
+	impl Source for Endless {
+		fn recv(&mut self, _: Duration) -> Result<Option<Delivery>, PipelineError> {
+			let i = self.0.fetch_add(1, Ordering::SeqCst);
+			let message = Message::new(i.to_string(), "key", &i.to_string(), "endless");
+			Ok(Some(Delivery::new(message, Ack::none())))
+		}
+	}
+
+	/// Never done writing.
+	struct Stuck(Receiver<()>);
 
-//     fn init() {}
+	impl Sink for Stuck {
+		fn write(&mut self, _: &[Message]) -> Result<(), PipelineError> {
+			let _ = self.0.recv();
+			Ok(())
+		}
+	}
 
-//     fn fetch_from(
-//         self,
-//         stream: &str,
-//         region_name: Option<&str>,
-//         kinesis_endpoint: Option<&str>,
-//     ) -> Self {
-//         let ks = KinesisHandler::new(s!(stream), region_name, kinesis_endpoint);
+	#[test]
+	fn blocked_outputs_stall_the_inputs() {
+		let mut firehose = Firehose::from_yaml(
+			"
+aliases:
+  events:
+    kinesis:
+      stream: test
 
-//         ks.get_records_stream();
-//     }
-// }
+input:
+  - name: events
+    kinesis:
+      alias: events
+      capacity: 4
+
+output:
+  - name: stuck
+    batch: 1
+    buffer: 1
+    stdout: {}
+",
+		)
+		.expect("Config should be valid");
+
+		let (_unblock, stuck) = unbounded();
+		let output = firehose.config.output[0].clone();
+		firehose.outputs.insert(
+			output.name.clone(),
+			OutputStage::start(&output, Box::new(Stuck(stuck))),
+		);
+		let read = Arc::new(AtomicUsize::new(0));
+		let input = InputStage::start(
+			s!("endless"),
+			Box::new(Endless(read.clone())),
+			firehose.records.0.clone(),
+		);
+		firehose.inputs.insert(s!("endless"), input);
+
+		let records = firehose.records.1.clone();
+		thread::spawn(move || {
+			for delivery in records.iter() {
+				firehose.dispatch(delivery);
+			}
+		});
+
+		thread::sleep(Duration::from_millis(200));
+		let stalled = read.load(Ordering::SeqCst);
+		thread::sleep(Duration::from_millis(200));
+		assert_eq!(stalled, read.load(Ordering::SeqCst));
+		// The channel, the buffer, the batch written and one in each stage
+		assert!(stalled <= 4 + 1 + 1 + 2, "{} records read", stalled);
+	}
+}
@@ -1,23 +1,921 @@
-use crossbeam::channel::unbounded;
+use crossbeam::channel::{bounded, never, unbounded, SendTimeoutError, TrySendError};
 
-use rand::RngCore;
 use bytes::Bytes;
-use rusoto_core::Region;
+use hyper::client::HttpConnector;
+use hyper_tls::HttpsConnector;
+use native_tls::{Certificate, TlsConnector};
+use rand::{Rng, RngCore};
+use rusoto_core::{DefaultCredentialsProvider, HttpClient, Region, RusotoError};
+use rusoto_credential::{EnvironmentProvider, ProfileProvider, StaticProvider};
 use rusoto_kinesis::{
-	GetRecordsInput, GetRecordsOutput, GetShardIteratorInput, Kinesis, KinesisClient,
-	ListShardsInput, PutRecordsInput, PutRecordsRequestEntry, Record, Shard,
+	CreateStreamError, CreateStreamInput, DecreaseStreamRetentionPeriodError,
+	DecreaseStreamRetentionPeriodInput, DeleteStreamError, DeleteStreamInput,
+	DescribeStreamSummaryError, DescribeStreamSummaryInput, GetRecordsError, GetRecordsInput,
+	GetRecordsOutput, GetShardIteratorError, GetShardIteratorInput,
+	IncreaseStreamRetentionPeriodError, IncreaseStreamRetentionPeriodInput, Kinesis, KinesisClient,
+	ListShardsError, ListShardsInput, PutRecordsError, PutRecordsInput, PutRecordsOutput,
+	PutRecordsRequestEntry, Shard, StreamDescriptionSummary, UpdateShardCountError,
+	UpdateShardCountInput,
 };
-use std::{str::FromStr, sync::Arc, thread, time::Duration};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::Value;
+use std::{
+	collections::{HashMap, HashSet},
+	fmt, fs, panic,
+	str::FromStr,
+	sync::{
+		atomic::{AtomicBool, Ordering},
+		Arc, Mutex,
+	},
+	thread::{self, JoinHandle},
+	time::{Duration, Instant},
+};
+
+use crate::kpl::{user_records, UserRecord};
+use crate::lease::{Leases, LEASE_TTL};
+use crate::producer::Producer;
+use crate::stream::{MergedStream, PutSink, ShardStream};
+
+#[derive(Debug)]
+pub enum KinesisError {
+	/// Over the throughput of a shard or the limits of the account.
+	Throttled(String),
+	/// A shard iterator not used within 5 minutes.
+	ExpiredIterator(String),
+	/// No such stream or shard, or the stream is not active.
+	NotFound(String),
+	/// The stream already exists, or is being created, deleted or updated.
+	InUse(String),
+	/// A request Kinesis or the client refuses as it is.
+	Invalid(String),
+	/// The credentials to sign requests with could not be loaded.
+	Credentials(String),
+	/// The request or its response got lost on the way.
+	Transport(String),
+	/// Anything else Kinesis refused, like a KMS key it cannot use.
+	Service(String),
+}
+
+impl KinesisError {
+	/// Whether the same call may succeed later.
+	pub fn is_retryable(&self) -> bool {
+		matches!(
+			self,
+			KinesisError::Throttled(_) | KinesisError::Transport(_)
+		)
+	}
+}
+
+impl fmt::Display for KinesisError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			KinesisError::Throttled(e) => write!(f, "throttled: {}", e),
+			KinesisError::ExpiredIterator(e) => write!(f, "expired iterator: {}", e),
+			KinesisError::NotFound(e) => write!(f, "not found: {}", e),
+			KinesisError::InUse(e) => write!(f, "in use: {}", e),
+			KinesisError::Invalid(e) => write!(f, "invalid request: {}", e),
+			KinesisError::Credentials(e) => write!(f, "credentials: {}", e),
+			KinesisError::Transport(e) => write!(f, "transport: {}", e),
+			KinesisError::Service(e) => write!(f, "{}", e),
+		}
+	}
+}
+
+impl<E: Into<KinesisError>> From<RusotoError<E>> for KinesisError {
+	fn from(e: RusotoError<E>) -> Self {
+		match e {
+			RusotoError::Service(e) => e.into(),
+			RusotoError::HttpDispatch(e) => KinesisError::Transport(e.to_string()),
+			RusotoError::Credentials(e) => KinesisError::Credentials(e.to_string()),
+			RusotoError::Validation(e) => KinesisError::Invalid(e),
+			RusotoError::ParseError(e) => KinesisError::Transport(e),
+			RusotoError::Unknown(res) => {
+				let message = format!("{}: {}", res.status, String::from_utf8_lossy(&res.body));
+				if res.status.is_server_error() {
+					KinesisError::Transport(message)
+				} else {
+					KinesisError::Service(message)
+				}
+			}
+		}
+	}
+}
+
+impl From<GetShardIteratorError> for KinesisError {
+	fn from(e: GetShardIteratorError) -> Self {
+		match e {
+			GetShardIteratorError::ProvisionedThroughputExceeded(e) => KinesisError::Throttled(e),
+			GetShardIteratorError::ResourceNotFound(e) => KinesisError::NotFound(e),
+			GetShardIteratorError::InvalidArgument(e) => KinesisError::Invalid(e),
+		}
+	}
+}
+
+impl From<GetRecordsError> for KinesisError {
+	fn from(e: GetRecordsError) -> Self {
+		match e {
+			GetRecordsError::ExpiredIterator(e) => KinesisError::ExpiredIterator(e),
+			GetRecordsError::ProvisionedThroughputExceeded(e)
+			| GetRecordsError::KMSThrottling(e) => KinesisError::Throttled(e),
+			GetRecordsError::ResourceNotFound(e) => KinesisError::NotFound(e),
+			GetRecordsError::InvalidArgument(e) => KinesisError::Invalid(e),
+			e => KinesisError::Service(e.to_string()),
+		}
+	}
+}
+
+impl From<ListShardsError> for KinesisError {
+	fn from(e: ListShardsError) -> Self {
+		match e {
+			ListShardsError::LimitExceeded(e) => KinesisError::Throttled(e),
+			ListShardsError::ResourceNotFound(e) => KinesisError::NotFound(e),
+			ListShardsError::ResourceInUse(e) => KinesisError::InUse(e),
+			ListShardsError::InvalidArgument(e) | ListShardsError::ExpiredNextToken(e) => {
+				KinesisError::Invalid(e)
+			}
+		}
+	}
+}
+
+impl From<CreateStreamError> for KinesisError {
+	fn from(e: CreateStreamError) -> Self {
+		match e {
+			CreateStreamError::LimitExceeded(e) => KinesisError::Throttled(e),
+			CreateStreamError::ResourceInUse(e) => KinesisError::InUse(e),
+			CreateStreamError::InvalidArgument(e) => KinesisError::Invalid(e),
+		}
+	}
+}
+
+impl From<DeleteStreamError> for KinesisError {
+	fn from(e: DeleteStreamError) -> Self {
+		match e {
+			DeleteStreamError::LimitExceeded(e) => KinesisError::Throttled(e),
+			DeleteStreamError::ResourceInUse(e) => KinesisError::InUse(e),
+			DeleteStreamError::ResourceNotFound(e) => KinesisError::NotFound(e),
+		}
+	}
+}
+
+impl From<DescribeStreamSummaryError> for KinesisError {
+	fn from(e: DescribeStreamSummaryError) -> Self {
+		match e {
+			DescribeStreamSummaryError::LimitExceeded(e) => KinesisError::Throttled(e),
+			DescribeStreamSummaryError::ResourceNotFound(e) => KinesisError::NotFound(e),
+		}
+	}
+}
+
+impl From<UpdateShardCountError> for KinesisError {
+	fn from(e: UpdateShardCountError) -> Self {
+		match e {
+			UpdateShardCountError::LimitExceeded(e) => KinesisError::Throttled(e),
+			UpdateShardCountError::ResourceInUse(e) => KinesisError::InUse(e),
+			UpdateShardCountError::ResourceNotFound(e) => KinesisError::NotFound(e),
+			UpdateShardCountError::InvalidArgument(e) => KinesisError::Invalid(e),
+		}
+	}
+}
+
+impl From<IncreaseStreamRetentionPeriodError> for KinesisError {
+	fn from(e: IncreaseStreamRetentionPeriodError) -> Self {
+		match e {
+			IncreaseStreamRetentionPeriodError::LimitExceeded(e) => KinesisError::Throttled(e),
+			IncreaseStreamRetentionPeriodError::ResourceInUse(e) => KinesisError::InUse(e),
+			IncreaseStreamRetentionPeriodError::ResourceNotFound(e) => KinesisError::NotFound(e),
+			IncreaseStreamRetentionPeriodError::InvalidArgument(e) => KinesisError::Invalid(e),
+		}
+	}
+}
+
+impl From<DecreaseStreamRetentionPeriodError> for KinesisError {
+	fn from(e: DecreaseStreamRetentionPeriodError) -> Self {
+		match e {
+			DecreaseStreamRetentionPeriodError::LimitExceeded(e) => KinesisError::Throttled(e),
+			DecreaseStreamRetentionPeriodError::ResourceInUse(e) => KinesisError::InUse(e),
+			DecreaseStreamRetentionPeriodError::ResourceNotFound(e) => KinesisError::NotFound(e),
+			DecreaseStreamRetentionPeriodError::InvalidArgument(e) => KinesisError::Invalid(e),
+		}
+	}
+}
+
+impl From<PutRecordsError> for KinesisError {
+	fn from(e: PutRecordsError) -> Self {
+		match e {
+			PutRecordsError::ProvisionedThroughputExceeded(e)
+			| PutRecordsError::KMSThrottling(e) => KinesisError::Throttled(e),
+			PutRecordsError::ResourceNotFound(e) => KinesisError::NotFound(e),
+			PutRecordsError::InvalidArgument(e) => KinesisError::Invalid(e),
+			e => KinesisError::Service(e.to_string()),
+		}
+	}
+}
+
+/// Where to start reading a shard, one of the shard iterator types of
+/// Kinesis. Written as `latest`, `trim_horizon`, `at_sequence_number:<n>`,
+/// `after_sequence_number:<n>` or `at_timestamp:<seconds since the epoch>`.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub enum StartPosition {
+	/// Records put from now on.
+	Latest,
+	/// The oldest record still in the shard.
+	#[default]
+	TrimHorizon,
+	AtSequenceNumber(String),
+	AfterSequenceNumber(String),
+	/// Seconds since the epoch, fractions allowed.
+	AtTimestamp(f64),
+}
+
+impl StartPosition {
+	pub fn iterator_type(&self) -> &'static str {
+		match self {
+			StartPosition::Latest => "LATEST",
+			StartPosition::TrimHorizon => "TRIM_HORIZON",
+			StartPosition::AtSequenceNumber(_) => "AT_SEQUENCE_NUMBER",
+			StartPosition::AfterSequenceNumber(_) => "AFTER_SEQUENCE_NUMBER",
+			StartPosition::AtTimestamp(_) => "AT_TIMESTAMP",
+		}
+	}
+
+	fn sequence_number(&self) -> Option<String> {
+		match self {
+			StartPosition::AtSequenceNumber(n) | StartPosition::AfterSequenceNumber(n) => {
+				Some(n.clone())
+			}
+			_ => None,
+		}
+	}
+
+	fn timestamp(&self) -> Option<f64> {
+		match self {
+			StartPosition::AtTimestamp(t) => Some(*t),
+			_ => None,
+		}
+	}
+
+	/// Position of a shard listed when reading started, `None` when it has
+	/// nothing to read from there: a closed shard gets no new records.
+	fn of(&self, shard: &Shard) -> Option<StartPosition> {
+		let closed = shard.sequence_number_range.ending_sequence_number.is_some();
+		match self {
+			StartPosition::Latest if closed => None,
+			position => Some(position.clone()),
+		}
+	}
+}
+
+impl FromStr for StartPosition {
+	type Err = String;
+
+	fn from_str(text: &str) -> Result<Self, Self::Err> {
+		let (kind, value) = match text.find(':') {
+			Some(i) => (&text[..i], Some(&text[i + 1..])),
+			None => (text, None),
+		};
+
+		match (kind, value) {
+			("latest", None) => Ok(StartPosition::Latest),
+			("trim_horizon", None) => Ok(StartPosition::TrimHorizon),
+			("at_sequence_number", Some(n)) if !n.is_empty() => {
+				Ok(StartPosition::AtSequenceNumber(s!(n)))
+			}
+			("after_sequence_number", Some(n)) if !n.is_empty() => {
+				Ok(StartPosition::AfterSequenceNumber(s!(n)))
+			}
+			("at_timestamp", Some(t)) => t
+				.parse()
+				.map(StartPosition::AtTimestamp)
+				.map_err(|_| format!("invalid timestamp `{}`, expected seconds since the epoch", t)),
+			_ => Err(format!(
+				"invalid position `{}`, expected `latest`, `trim_horizon`, `at_sequence_number:<n>`, `after_sequence_number:<n>` or `at_timestamp:<seconds>`",
+				text
+			)),
+		}
+	}
+}
+
+impl fmt::Display for StartPosition {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			StartPosition::Latest => write!(f, "latest"),
+			StartPosition::TrimHorizon => write!(f, "trim_horizon"),
+			StartPosition::AtSequenceNumber(n) => write!(f, "at_sequence_number:{}", n),
+			StartPosition::AfterSequenceNumber(n) => write!(f, "after_sequence_number:{}", n),
+			StartPosition::AtTimestamp(t) => write!(f, "at_timestamp:{}", t),
+		}
+	}
+}
+
+impl Serialize for StartPosition {
+	fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+		serializer.serialize_str(&self.to_string())
+	}
+}
+
+impl<'de> Deserialize<'de> for StartPosition {
+	fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+		String::deserialize(deserializer)?
+			.parse()
+			.map_err(de::Error::custom)
+	}
+}
+
+/// Partition keys are at most that many characters.
+const MAX_PARTITION_KEY: usize = 256;
+
+/// How the partition key of a record is chosen. Records sharing a key go to
+/// the same shard and keep their order. Written as `random`, `hash`,
+/// `field:<path>` or `explicit_hash_key:<n>`.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub enum PartitionKeyStrategy {
+	/// Spreads records evenly, without any order.
+	#[default]
+	Random,
+	/// Value of a dotted path like `metadata.tenant`, random for records
+	/// without it.
+	Field(String),
+	/// MD5 of the data, the same records always go to the same shard.
+	Hash,
+	/// Every record to the shard whose hash key range holds this decimal
+	/// 128 bits hash key.
+	ExplicitHashKey(String),
+}
+
+impl PartitionKeyStrategy {
+	/// Partition key and explicit hash key of a record, `record` being its
+	/// data parsed as JSON when it is.
+	pub fn keys(&self, data: &[u8], record: Option<&Value>) -> (String, Option<String>) {
+		let random = || rand::thread_rng().next_u32().to_string();
+
+		match self {
+			PartitionKeyStrategy::Random => (random(), None),
+			PartitionKeyStrategy::Field(path) => {
+				let key = match record.and_then(|r| path.split('.').try_fold(r, |v, k| v.get(k))) {
+					Some(Value::String(key)) => key.chars().take(MAX_PARTITION_KEY).collect(),
+					Some(Value::Null) | None => String::new(),
+					Some(key) => key.to_string().chars().take(MAX_PARTITION_KEY).collect(),
+				};
+				(if key.is_empty() { random() } else { key }, None)
+			}
+			PartitionKeyStrategy::Hash => (format!("{:x}", md5::compute(data)), None),
+			PartitionKeyStrategy::ExplicitHashKey(key) => (key.clone(), Some(key.clone())),
+		}
+	}
+}
+
+impl FromStr for PartitionKeyStrategy {
+	type Err = String;
+
+	fn from_str(text: &str) -> Result<Self, Self::Err> {
+		let (kind, value) = match text.find(':') {
+			Some(i) => (&text[..i], Some(&text[i + 1..])),
+			None => (text, None),
+		};
+
+		match (kind, value) {
+			("random", None) => Ok(PartitionKeyStrategy::Random),
+			("hash", None) => Ok(PartitionKeyStrategy::Hash),
+			("field", Some(path)) if !path.split('.').any(str::is_empty) => {
+				Ok(PartitionKeyStrategy::Field(s!(path)))
+			}
+			("explicit_hash_key", Some(key)) => match key.parse::<u128>() {
+				Ok(_) => Ok(PartitionKeyStrategy::ExplicitHashKey(s!(key))),
+				Err(_) => Err(format!(
+					"invalid hash key `{}`, expected a number below 2^128",
+					key
+				)),
+			},
+			_ => Err(format!(
+				"invalid partition key `{}`, expected `random`, `hash`, `field:<path>` or `explicit_hash_key:<n>`",
+				text
+			)),
+		}
+	}
+}
+
+impl fmt::Display for PartitionKeyStrategy {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			PartitionKeyStrategy::Random => write!(f, "random"),
+			PartitionKeyStrategy::Field(path) => write!(f, "field:{}", path),
+			PartitionKeyStrategy::Hash => write!(f, "hash"),
+			PartitionKeyStrategy::ExplicitHashKey(key) => write!(f, "explicit_hash_key:{}", key),
+		}
+	}
+}
+
+impl Serialize for PartitionKeyStrategy {
+	fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+		serializer.serialize_str(&self.to_string())
+	}
+}
+
+impl<'de> Deserialize<'de> for PartitionKeyStrategy {
+	fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+		String::deserialize(deserializer)?
+			.parse()
+			.map_err(de::Error::custom)
+	}
+}
+
+/// Which shards of a stream to read and where each one starts. Shards
+/// created by a split or a merge while reading always start from their
+/// oldest record.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Start {
+	/// Every shard from the same position.
+	All(StartPosition),
+	/// Only this shard, without the children it may have.
+	Shard(String, StartPosition),
+	/// Every shard from its own position, those not listed from the last one.
+	PerShard(HashMap<String, StartPosition>, StartPosition),
+}
+
+impl From<StartPosition> for Start {
+	fn from(position: StartPosition) -> Self {
+		Start::All(position)
+	}
+}
+
+impl Start {
+	/// Position of a shard, telling whether it was listed when reading
+	/// started, `None` when it has nothing to read from there.
+	pub(crate) fn position(&self, shard: &Shard, initial: bool) -> Option<StartPosition> {
+		let position = match self {
+			_ if !initial => return Some(StartPosition::TrimHorizon),
+			Start::All(position) | Start::Shard(_, position) => position,
+			Start::PerShard(positions, rest) => positions.get(&shard.shard_id).unwrap_or(rest),
+		};
+		position.of(shard)
+	}
+}
+
+/// Shortest wait between two `GetRecords` calls on a shard, which takes 5
+/// calls per second at most.
+pub const MIN_POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// How a shard reader calls `GetRecords`: as often as the shard allows
+/// while it is behind or records keep coming, less and less often while it
+/// stays idle.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Polling {
+	/// Records a call returns at most, Kinesis returns up to 10000 without.
+	pub limit: Option<i64>,
+	/// Wait between calls while records come, never under
+	/// `MIN_POLL_INTERVAL`.
+	pub min: Duration,
+	/// Wait between calls on an idle shard at most, reached by doubling
+	/// `min` for each call in a row that got nothing.
+	pub max: Duration,
+}
+
+impl Default for Polling {
+	fn default() -> Self {
+		Polling {
+			limit: None,
+			min: MIN_POLL_INTERVAL,
+			max: Duration::from_secs(2),
+		}
+	}
+}
+
+impl Polling {
+	/// Wait from the start of a call to the next one, given how far behind
+	/// the tip of the shard the call left the reader and how many calls in
+	/// a row got no records.
+	pub(crate) fn wait(&self, millis_behind_latest: Option<i64>, empty: u32) -> Duration {
+		let min = self.min.max(MIN_POLL_INTERVAL);
+		if millis_behind_latest.unwrap_or(0) > 0 {
+			// Empty calls happen while catching up too, on gaps in the shard
+			return min;
+		}
+
+		min.checked_mul(1 << empty.min(16))
+			.map_or(self.max, |wait| wait.min(self.max))
+			.max(min)
+	}
+}
+
+/// Records waiting in the channel of a stream at most, by default.
+pub const DEFAULT_CAPACITY: usize = 10_000;
+
+/// Stall of a shard reader worth a line in the logs.
+const STALL_REPORT: Duration = Duration::from_secs(10);
+
+/// How the readers of the streams of a handler and of its clones went.
+#[derive(Debug, Default)]
+pub struct ReadMetrics {
+	stalled: Mutex<HashMap<String, Duration>>,
+}
+
+impl ReadMetrics {
+	/// Time each shard reader spent waiting for room in the channel of its
+	/// stream, so how long whoever receives the records held it back.
+	pub fn stalled(&self) -> HashMap<String, Duration> {
+		self.stalled.lock().expect("Metrics lock").clone()
+	}
+
+	/// Adds to the stalled time of a shard, returns its total.
+	fn stall(&self, shard_id: &str, stalled: Duration) -> Duration {
+		let mut shards = self.stalled.lock().expect("Metrics lock");
+		let total = shards.entry(s!(shard_id)).or_default();
+		*total += stalled;
+		*total
+	}
+}
+
+/// Sends `item` once the channel has room and tells how long it was full,
+/// `None` when nobody receives it anymore or `stop` is set meanwhile.
+fn send_waiting<T>(s: &crossbeam::Sender<T>, item: T, stop: &AtomicBool) -> Option<Duration> {
+	let mut item = match s.try_send(item) {
+		Ok(()) => return Some(Duration::default()),
+		Err(TrySendError::Full(item)) => item,
+		Err(TrySendError::Disconnected(_)) => return None,
+	};
+	let full = Instant::now();
+
+	loop {
+		if stop.load(Ordering::SeqCst) {
+			return None;
+		}
+		match s.send_timeout(item, Duration::from_secs(1)) {
+			Ok(()) => return Some(full.elapsed()),
+			Err(SendTimeoutError::Timeout(back)) => item = back,
+			Err(SendTimeoutError::Disconnected(_)) => return None,
+		}
+	}
+}
+
+/// Status of a stream that can be read and written.
+pub const ACTIVE: &str = "ACTIVE";
+
+/// Wait between two looks at a stream being created or updated.
+const STATUS_POLL: Duration = Duration::from_secs(1);
+
+/// Wait before reading again a shard whose reader failed.
+const RESTART_AFTER: Duration = Duration::from_secs(30);
+
+/// Records read from a stream by threads of their own. Dropping it stops
+/// the readers without waiting for them and releases their leases once
+/// they are done, `shutdown` waits and keeps the leases until `release`.
+pub struct RecordStream<T> {
+	records: crossbeam::Receiver<T>,
+	stop: Arc<AtomicBool>,
+	thread: Option<JoinHandle<Option<Leases>>>,
+	leases: Option<Leases>,
+}
+
+impl<T> RecordStream<T> {
+	pub fn records(&self) -> &crossbeam::Receiver<T> {
+		&self.records
+	}
+
+	/// Stops polling, waits for the reader threads to end and returns the
+	/// records they sent but nobody received yet. The leases are kept so no
+	/// other worker reads the shards before their checkpoints are saved.
+	pub fn shutdown(&mut self) -> Vec<T> {
+		self.stop.store(true, Ordering::SeqCst);
+		if let Some(thread) = self.thread.take() {
+			match thread.join() {
+				Ok(leases) => self.leases = leases,
+				Err(_) => eprintln!("A Kinesis reader panicked"),
+			}
+		}
+
+		self.records.try_iter().collect()
+	}
+
+	/// Gives up the leases kept by `shutdown`, for other workers to read the
+	/// shards from their checkpoints.
+	pub fn release(&mut self) {
+		if let Some(mut leases) = self.leases.take() {
+			leases.release_all();
+		}
+	}
+}
+
+impl<T> Drop for RecordStream<T> {
+	fn drop(&mut self) {
+		self.stop.store(true, Ordering::SeqCst);
+		self.release();
+		if let Some(thread) = self.thread.take() {
+			thread::spawn(move || {
+				if let Ok(Some(mut leases)) = thread.join() {
+					leases.release_all();
+				}
+			});
+		}
+	}
+}
+
+/// How the thread reading a shard ended.
+enum Reader {
+	/// The shard is closed and every record of it was sent.
+	Closed(String),
+	/// Kinesis refused a call the shard cannot be read without, reading may
+	/// start again from the position given.
+	Failed(String, StartPosition),
+	/// Nobody receives the records anymore.
+	Stopped,
+}
+
+/// Shards of a stream, telling which can be read once others are drained.
+pub(crate) struct ShardGraph {
+	shards: Vec<Shard>,
+	drained: HashSet<String>,
+}
+
+impl ShardGraph {
+	pub(crate) fn new(shards: Vec<Shard>) -> Self {
+		ShardGraph {
+			shards,
+			drained: HashSet::new(),
+		}
+	}
+
+	/// Adds the shards created since the last listing.
+	pub(crate) fn update(&mut self, shards: Vec<Shard>) {
+		for shard in shards {
+			if !self.shards.iter().any(|s| s.shard_id == shard.shard_id) {
+				self.shards.push(shard);
+			}
+		}
+	}
+
+	pub(crate) fn get(&self, shard_id: &str) -> Option<&Shard> {
+		self.shards.iter().find(|s| s.shard_id == shard_id)
+	}
+
+	pub(crate) fn drained(&mut self, shard_id: &str) {
+		self.drained.insert(s!(shard_id));
+	}
+
+	/// Shards not drained yet whose parents were, parents past the retention
+	/// period are not listed anymore and do not count.
+	pub(crate) fn readable(&self) -> Vec<String> {
+		let listed = |id: &String| self.shards.iter().any(|s| &s.shard_id == id);
+
+		self.shards
+			.iter()
+			.filter(|shard| !self.drained.contains(&shard.shard_id))
+			.filter(|shard| {
+				[&shard.parent_shard_id, &shard.adjacent_parent_shard_id]
+					.iter()
+					.filter_map(|parent| parent.as_ref())
+					.all(|parent| !listed(parent) || self.drained.contains(parent))
+			})
+			.map(|shard| shard.shard_id.clone())
+			.collect()
+	}
+}
+
+/// How calls failing for a while, when throttled for instance, are made
+/// again, and the records Kinesis refuses in a `PutRecords` call put again.
+/// Waits double from `base` up to `max`, each one picked at random below
+/// that bound so clients do not retry together.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetryPolicy {
+	/// Calls made at most for a request or a record, the first one included.
+	pub attempts: u32,
+	pub base: Duration,
+	pub max: Duration,
+}
+
+impl Default for RetryPolicy {
+	fn default() -> Self {
+		RetryPolicy {
+			attempts: 5,
+			base: Duration::from_millis(100),
+			max: Duration::from_secs(5),
+		}
+	}
+}
+
+impl RetryPolicy {
+	/// Random wait before the retry following `attempt`, counted from 1.
+	pub(crate) fn backoff(&self, attempt: u32) -> Duration {
+		let bound = self
+			.base
+			.checked_mul(1 << (attempt - 1).min(16))
+			.map_or(self.max, |b| b.min(self.max));
+
+		Duration::from_millis(rand::thread_rng().gen_range(0, bound.as_millis() as u64 + 1))
+	}
+
+	/// Result of `call` once it succeeds, fails for good or the attempts
+	/// are spent.
+	fn call<O, C>(&self, mut call: C) -> Result<O, KinesisError>
+	where
+		C: FnMut() -> Result<O, KinesisError>,
+	{
+		let mut attempt = 1;
+
+		loop {
+			match call() {
+				Err(ref e) if e.is_retryable() && attempt < self.attempts => {
+					thread::sleep(self.backoff(attempt));
+					attempt += 1;
+				}
+				result => return result,
+			}
+		}
+	}
+}
+
+/// A record still refused once every attempt was made.
+#[derive(Debug, Clone)]
+pub struct FailedRecord {
+	pub entry: PutRecordsRequestEntry,
+	/// Like `ProvisionedThroughputExceededException`.
+	pub error_code: String,
+	pub error_message: String,
+}
+
+/// Receives the records given up on by `put_records_stream`.
+pub type DeadLetter = Arc<dyn Fn(FailedRecord) + Send + Sync>;
+
+/// Records of `entries` the `PutRecords` call that sent them refused.
+pub(crate) fn refused(
+	entries: Vec<PutRecordsRequestEntry>,
+	output: PutRecordsOutput,
+) -> Vec<FailedRecord> {
+	if output.failed_record_count.unwrap_or(0) == 0 {
+		return vec![];
+	}
+
+	entries
+		.into_iter()
+		.zip(output.records)
+		.filter_map(|(entry, result)| {
+			Some(FailedRecord {
+				entry,
+				error_code: result.error_code?,
+				error_message: result.error_message.unwrap_or_default(),
+			})
+		})
+		.collect()
+}
+
+/// Puts `entries` with `put`, putting again the ones refused as `retry`
+/// says. Results come in the order of the entries sent, failed ones have an
+/// error code.
+fn put_with_retry<P>(
+	mut entries: Vec<PutRecordsRequestEntry>,
+	retry: &RetryPolicy,
+	mut put: P,
+) -> Result<Vec<FailedRecord>, KinesisError>
+where
+	P: FnMut(Vec<PutRecordsRequestEntry>) -> Result<PutRecordsOutput, KinesisError>,
+{
+	let mut attempt = 1;
+
+	loop {
+		let output = put(entries.clone())?;
+		let failed = refused(entries, output);
+		if attempt >= retry.attempts || failed.is_empty() {
+			return Ok(failed);
+		}
+
+		thread::sleep(retry.backoff(attempt));
+		attempt += 1;
+		entries = failed.into_iter().map(|f| f.entry).collect();
+	}
+}
+
+/// Who requests are signed as.
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub enum Credentials {
+	/// The environment, the profile file, then the container or instance
+	/// role.
+	#[default]
+	Default,
+	Static {
+		access_key: String,
+		secret_key: String,
+		session_token: Option<String>,
+	},
+	/// A profile of `~/.aws/credentials`, or of `file`.
+	Profile { name: String, file: Option<String> },
+	/// `AWS_ACCESS_KEY_ID`, `AWS_SECRET_ACCESS_KEY` and `AWS_SESSION_TOKEN`.
+	Environment,
+}
+
+/// How the client connects to Kinesis.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct ClientOptions {
+	/// PEM file of CA certificates trusted on top of those of the system,
+	/// for an endpoint with a self-signed certificate.
+	pub ca_bundle: Option<String>,
+	/// Accepts any certificate and host name, for local emulators only.
+	pub insecure: bool,
+	pub credentials: Credentials,
+}
+
+/// Certificates of a PEM bundle, native-tls reads a single one at a time.
+fn certificates(pem: &str) -> Result<Vec<Certificate>, native_tls::Error> {
+	const END: &str = "-----END CERTIFICATE-----";
+
+	pem.split_inclusive(END)
+		.filter(|block| block.contains(END))
+		.map(|block| Certificate::from_pem(block.trim().as_bytes()))
+		.collect()
+}
+
+fn client(region: Region, options: &ClientOptions) -> Result<KinesisClient, KinesisError> {
+	let mut tls = TlsConnector::builder();
+	if let Some(path) = &options.ca_bundle {
+		let invalid = |e: &dyn fmt::Display| KinesisError::Invalid(format!("{}: {}", path, e));
+		let pem = fs::read_to_string(path).map_err(|e| invalid(&e))?;
+		let certificates = certificates(&pem).map_err(|e| invalid(&e))?;
+		if certificates.is_empty() {
+			return Err(invalid(&"no certificate found"));
+		}
+		for certificate in certificates {
+			tls.add_root_certificate(certificate);
+		}
+	}
+	if options.insecure {
+		tls.danger_accept_invalid_certs(true)
+			.danger_accept_invalid_hostnames(true);
+	}
+	let tls = tls
+		.build()
+		.map_err(|e| KinesisError::Invalid(format!("tls: {}", e)))?;
+	let mut http = HttpConnector::new(4);
+	http.enforce_http(false);
+	let dispatcher = HttpClient::from_connector(HttpsConnector::from((http, tls)));
+
+	let credentials = |e: rusoto_credential::CredentialsError| KinesisError::Credentials(e.message);
+	Ok(match &options.credentials {
+		Credentials::Default => {
+			let provider = DefaultCredentialsProvider::new().map_err(credentials)?;
+			KinesisClient::new_with(dispatcher, provider, region)
+		}
+		Credentials::Static {
+			access_key,
+			secret_key,
+			session_token,
+		} => {
+			let provider = StaticProvider::new(
+				access_key.clone(),
+				secret_key.clone(),
+				session_token.clone(),
+				None,
+			);
+			KinesisClient::new_with(dispatcher, provider, region)
+		}
+		Credentials::Profile { name, file } => {
+			let mut provider = ProfileProvider::new().map_err(credentials)?;
+			provider.set_profile(name.as_str());
+			if let Some(file) = file {
+				provider.set_file_path(file.as_str());
+			}
+			KinesisClient::new_with(dispatcher, provider, region)
+		}
+		Credentials::Environment => {
+			KinesisClient::new_with(dispatcher, EnvironmentProvider::default(), region)
+		}
+	})
+}
 
 #[derive(Clone)]
 pub struct KinesisHandler {
 	region: Region,
 	client: Arc<KinesisClient>,
 	stream: String,
+	retry: RetryPolicy,
+	polling: Polling,
+	capacity: usize,
+	metrics: Arc<ReadMetrics>,
+	dead_letter: Option<DeadLetter>,
+	aggregation: bool,
 }
 
 impl KinesisHandler {
-	pub fn new(stream: String, region_name: Option<&str>, kinesis_endpoint: Option<&str>) -> Self {
+	pub fn new(
+		stream: String,
+		region_name: Option<&str>,
+		kinesis_endpoint: Option<&str>,
+	) -> Result<Self, KinesisError> {
+		KinesisHandler::new_with_options(
+			stream,
+			region_name,
+			kinesis_endpoint,
+			&ClientOptions::default(),
+		)
+	}
+
+	/// Like `new`, with the certificates trusted and the credentials used
+	/// `options` gives. A CA bundle that cannot be read or credentials that
+	/// cannot be found fail right away.
+	pub fn new_with_options(
+		stream: String,
+		region_name: Option<&str>,
+		kinesis_endpoint: Option<&str>,
+		options: &ClientOptions,
+	) -> Result<Self, KinesisError> {
 		let region_name = region_name.unwrap_or("local-stack-1");
 
 		let region = match kinesis_endpoint {
@@ -25,180 +923,1054 @@ impl KinesisHandler {
 				name: s!(region_name),
 				endpoint: s!(endpoint),
 			},
-			None => Region::from_str(region_name).expect("Cannot parse this region"),
+			None => Region::from_str(region_name)
+				.map_err(|_| KinesisError::Invalid(format!("unknown region {}", region_name)))?,
 		};
 
-		KinesisHandler {
-			client: Arc::new(KinesisClient::new(region.clone())),
+		Ok(KinesisHandler {
+			client: Arc::new(client(region.clone(), options)?),
 			region,
 			stream,
+			retry: RetryPolicy::default(),
+			polling: Polling::default(),
+			capacity: DEFAULT_CAPACITY,
+			metrics: Arc::new(ReadMetrics::default()),
+			dead_letter: None,
+			aggregation: false,
+		})
+	}
+
+	/// Replaces how failed calls are made again and refused records put
+	/// again.
+	pub fn with_retry(mut self, retry: RetryPolicy) -> Self {
+		self.retry = retry;
+		self
+	}
+
+	/// Replaces how often shards are read and how many records a call
+	/// takes.
+	pub fn with_polling(mut self, polling: Polling) -> Self {
+		self.polling = polling;
+		self
+	}
+
+	/// Replaces how many records wait in the channel of a stream at most,
+	/// readers stop polling while it is full.
+	pub fn with_capacity(mut self, capacity: usize) -> Self {
+		self.capacity = capacity.max(1);
+		self
+	}
+
+	/// How the readers of the streams of this handler went, shared with its
+	/// clones.
+	pub fn metrics(&self) -> Arc<ReadMetrics> {
+		self.metrics.clone()
+	}
+
+	/// Hands the records `put_records_stream` and `put_sink` give up on to
+	/// `dead_letter` besides reporting them.
+	pub fn with_dead_letter<F>(mut self, dead_letter: F) -> Self
+	where
+		F: Fn(FailedRecord) + Send + Sync + 'static,
+	{
+		self.dead_letter = Some(Arc::new(dead_letter));
+		self
+	}
+
+	pub(crate) fn dead_letter(&self) -> Option<DeadLetter> {
+		self.dead_letter.clone()
+	}
+
+	/// Packs the records `put_records_stream` puts into KPL aggregated
+	/// records, fewer and larger, which consumers using the KPL or
+	/// `get_records_stream` unpack.
+	pub fn with_aggregation(mut self) -> Self {
+		self.aggregation = true;
+		self
+	}
+
+	pub(crate) fn aggregation(&self) -> bool {
+		self.aggregation
+	}
+
+	pub(crate) fn client(&self) -> Arc<KinesisClient> {
+		self.client.clone()
+	}
+
+	pub(crate) fn retry(&self) -> &RetryPolicy {
+		&self.retry
+	}
+
+	pub(crate) fn polling(&self) -> &Polling {
+		&self.polling
+	}
+
+	pub(crate) fn iterator_input(
+		&self,
+		shard_id: String,
+		position: &StartPosition,
+	) -> GetShardIteratorInput {
+		GetShardIteratorInput {
+			shard_id,
+			shard_iterator_type: position.iterator_type().to_string(),
+			starting_sequence_number: position.sequence_number(),
+			stream_name: self.stream.clone(),
+			timestamp: position.timestamp(),
 		}
 	}
 
-	pub fn create_record_from<T>(data: T) -> PutRecordsRequestEntry
+	/// A page of `ListShards`, the first one without a token.
+	pub(crate) fn shards_input(&self, next_token: Option<String>) -> ListShardsInput {
+		ListShardsInput {
+			exclusive_start_shard_id: None,
+			max_results: None,
+			stream_creation_timestamp: None,
+			// The stream cannot be given along with a token
+			stream_name: match next_token {
+				Some(_) => None,
+				None => Some(self.stream.clone()),
+			},
+			next_token,
+		}
+	}
+
+	pub(crate) fn records_input(&self, it: &str) -> GetRecordsInput {
+		GetRecordsInput {
+			limit: self.polling.limit,
+			shard_iterator: s!(it),
+		}
+	}
+
+	/// Fails with `Invalid` when `data` cannot be serialized to JSON.
+	pub fn create_record_from<T>(
+		data: T,
+		strategy: &PartitionKeyStrategy,
+	) -> Result<PutRecordsRequestEntry, KinesisError>
 	where
 		T: serde::Serialize,
 	{
-		let vec8 = serde_json::to_vec(&data).expect("Failed to parse structure");
+		let invalid = |e: serde_json::Error| KinesisError::Invalid(e.to_string());
+		let record = serde_json::to_value(&data).map_err(invalid)?;
+		let vec8 = serde_json::to_vec(&record).map_err(invalid)?;
+		let (partition_key, explicit_hash_key) = strategy.keys(&vec8, Some(&record));
 
-		PutRecordsRequestEntry {
+		Ok(PutRecordsRequestEntry {
 			data: Bytes::from(vec8),
-			explicit_hash_key: None,
-			partition_key: rand::thread_rng().next_u32().to_string(),
-		}
+			explicit_hash_key,
+			partition_key,
+		})
 	}
 
-	pub fn create_batch_from<T>(&self, data: Vec<T>) -> PutRecordsInput
+	pub fn create_batch_from<T>(
+		&self,
+		data: Vec<T>,
+		strategy: &PartitionKeyStrategy,
+	) -> Result<PutRecordsInput, KinesisError>
 	where
 		T: serde::Serialize,
 	{
-		PutRecordsInput {
+		Ok(PutRecordsInput {
 			records: data
 				.into_iter()
-				.map(KinesisHandler::create_record_from)
-				.collect(),
+				.map(|d| KinesisHandler::create_record_from(d, strategy))
+				.collect::<Result<_, _>>()?,
 			stream_name: self.stream.clone(),
-		}
+		})
+	}
+
+	pub fn region(&self) -> &Region {
+		&self.region
+	}
+
+	pub fn stream(&self) -> &str {
+		&self.stream
+	}
+
+	/// Creates the stream, `CREATING` until it is `ACTIVE`.
+	pub fn create_stream(&self, shard_count: i64) -> Result<(), KinesisError> {
+		let input = CreateStreamInput {
+			shard_count,
+			stream_name: self.stream.clone(),
+		};
+
+		self.retry
+			.call(|| Ok(self.client.create_stream(input.clone()).sync()?))
 	}
 
-	pub fn list_shards(
+	/// Deletes the stream along with its records, `DELETING` until it is
+	/// gone.
+	pub fn delete_stream(&self) -> Result<(), KinesisError> {
+		let input = DeleteStreamInput {
+			enforce_consumer_deletion: None,
+			stream_name: self.stream.clone(),
+		};
+
+		self.retry
+			.call(|| Ok(self.client.delete_stream(input.clone()).sync()?))
+	}
+
+	/// Status, shards and retention of the stream.
+	pub fn describe_stream_summary(&self) -> Result<StreamDescriptionSummary, KinesisError> {
+		let input = DescribeStreamSummaryInput {
+			stream_name: self.stream.clone(),
+		};
+		let output = self
+			.retry
+			.call(|| Ok(self.client.describe_stream_summary(input.clone()).sync()?))?;
+
+		Ok(output.stream_description_summary)
+	}
+
+	/// Summary of the stream once it is `ACTIVE`, `NotFound` when it is not
+	/// after `timeout` or when it is being deleted.
+	pub fn wait_until_active(
 		&self,
-	) -> rusoto_core::RusotoFuture<rusoto_kinesis::ListShardsOutput, rusoto_kinesis::ListShardsError>
-	{
-		self.client.list_shards(ListShardsInput {
-			exclusive_start_shard_id: None,
-			max_results: None,
-			next_token: None,
-			stream_creation_timestamp: None,
-			stream_name: Some(self.stream.clone()),
-		})
+		timeout: Duration,
+	) -> Result<StreamDescriptionSummary, KinesisError> {
+		let started = Instant::now();
+
+		loop {
+			let summary = self.describe_stream_summary()?;
+			if summary.stream_status == ACTIVE {
+				return Ok(summary);
+			}
+			if summary.stream_status == "DELETING" || started.elapsed() >= timeout {
+				return Err(KinesisError::NotFound(format!(
+					"stream {} is {}",
+					self.stream, summary.stream_status
+				)));
+			}
+			thread::sleep(STATUS_POLL);
+		}
+	}
+
+	/// Splits or merges shards until the stream has `target` open ones of
+	/// even size, `UPDATING` meanwhile.
+	pub fn update_shard_count(&self, target: i64) -> Result<(), KinesisError> {
+		let input = UpdateShardCountInput {
+			scaling_type: s!("UNIFORM_SCALING"),
+			stream_name: self.stream.clone(),
+			target_shard_count: target,
+		};
+
+		self.retry
+			.call(|| Ok(self.client.update_shard_count(input.clone()).sync()?))?;
+		Ok(())
+	}
+
+	/// Keeps records `hours` hours, from 24 up to 168.
+	pub fn set_retention(&self, hours: i64) -> Result<(), KinesisError> {
+		let current = self.describe_stream_summary()?.retention_period_hours;
+		if hours > current {
+			let input = IncreaseStreamRetentionPeriodInput {
+				retention_period_hours: hours,
+				stream_name: self.stream.clone(),
+			};
+			self.retry.call(|| {
+				Ok(self
+					.client
+					.increase_stream_retention_period(input.clone())
+					.sync()?)
+			})
+		} else if hours < current {
+			let input = DecreaseStreamRetentionPeriodInput {
+				retention_period_hours: hours,
+				stream_name: self.stream.clone(),
+			};
+			self.retry.call(|| {
+				Ok(self
+					.client
+					.decrease_stream_retention_period(input.clone())
+					.sync()?)
+			})
+		} else {
+			Ok(())
+		}
+	}
+
+	/// Creates the stream with `shard_count` shards unless it exists, and
+	/// waits until it is `ACTIVE` either way. Tells whether it was created.
+	pub fn ensure_stream(&self, shard_count: i64, timeout: Duration) -> Result<bool, KinesisError> {
+		let created = match self.describe_stream_summary() {
+			Ok(_) => false,
+			Err(KinesisError::NotFound(_)) => match self.create_stream(shard_count) {
+				Ok(()) => true,
+				// Created by someone else meanwhile
+				Err(KinesisError::InUse(_)) => false,
+				Err(e) => return Err(e),
+			},
+			Err(e) => return Err(e),
+		};
+
+		self.wait_until_active(timeout)?;
+		Ok(created)
+	}
+
+	/// Every shard of the stream, closed ones included, across all the pages
+	/// of `ListShards`.
+	pub fn list_shards(&self) -> Result<Vec<Shard>, KinesisError> {
+		let mut shards = Vec::new();
+		let mut next_token = None;
+
+		loop {
+			let input = self.shards_input(next_token);
+			let page = self
+				.retry
+				.call(|| Ok(self.client.list_shards(input.clone()).sync()?))?;
+
+			shards.extend(page.shards.unwrap_or_default());
+			next_token = page.next_token;
+			if next_token.is_none() {
+				return Ok(shards);
+			}
+		}
 	}
 
-	// TODO: Make this return a stream of records
 	pub fn get_shard_iterator(
 		&self,
 		shard_id: String,
-		iterator_type: String,
-		starting_sequence_number: Option<String>,
-	) -> String {
-		self.client
-			.get_shard_iterator(GetShardIteratorInput {
-				shard_id,
-				shard_iterator_type: iterator_type,
-				starting_sequence_number,
+		position: &StartPosition,
+	) -> Result<String, KinesisError> {
+		let input = self.iterator_input(shard_id, position);
+		let output = self
+			.retry
+			.call(|| Ok(self.client.get_shard_iterator(input.clone()).sync()?))?;
+
+		output.shard_iterator.ok_or_else(|| {
+			KinesisError::NotFound(format!("no iterator for shard {}", input.shard_id))
+		})
+	}
+
+	/// Makes one `PutRecords` call, records it refuses are not put again.
+	pub fn put_records(&self, records: PutRecordsInput) -> Result<PutRecordsOutput, KinesisError> {
+		self.retry
+			.call(|| Ok(self.client.put_records(records.clone()).sync()?))
+	}
+
+	/// Puts `entries` in the stream, retrying the records refused as the
+	/// retry policy says, and returns those still refused after that. Only
+	/// a failure of the whole call is an error.
+	pub fn put_entries(
+		&self,
+		entries: Vec<PutRecordsRequestEntry>,
+	) -> Result<Vec<FailedRecord>, KinesisError> {
+		put_with_retry(entries, &self.retry, |records| {
+			self.put_records(PutRecordsInput {
+				records,
 				stream_name: self.stream.clone(),
-				timestamp: None,
 			})
-			.sync()
-			.expect("Iterator not found")
-			.shard_iterator
-			.expect("Shard iterator is empty")
+		})
 	}
 
-	pub fn put_records(&self, records: PutRecordsInput) {
-		// NOTE: Get the output to gather some stats
-		self.client
-			.put_records(records)
-			.sync()
-			.expect("Put records failed");
+	pub fn get_records(&self, it: &str) -> Result<GetRecordsOutput, KinesisError> {
+		let input = self.records_input(it);
+
+		self.retry
+			.call(|| Ok(self.client.get_records(input.clone()).sync()?))
 	}
 
-	pub fn get_records(&self, it: &String) -> GetRecordsOutput {
-		self.client
-			.get_records(GetRecordsInput {
-				limit: None,
-				shard_iterator: it.to_string(),
-			})
-			.sync()
-			.expect("Failed fetching records")
+	/// Sends the records of a shard from a thread of its own, as `wrap`
+	/// makes them from the records their producers put, until the shard is
+	/// closed and read to its end, nobody receives them anymore or `stop` is
+	/// set. Polling pauses while the channel is full, the time it waited
+	/// goes to the metrics. An expired iterator is replaced by one right
+	/// after the last record sent, backing off as the retries do, and ends
+	/// the reading once it expired as many times in a row as they allow, as
+	/// does any other error they did not get past.
+	fn read_shard<T, F>(
+		&self,
+		shard_id: String,
+		mut position: StartPosition,
+		s: crossbeam::Sender<T>,
+		done: crossbeam::Sender<Reader>,
+		stop: Arc<AtomicBool>,
+		wrap: Arc<F>,
+	) -> JoinHandle<()>
+	where
+		T: Send + 'static,
+		F: Fn(&str, UserRecord) -> T + Send + Sync + 'static,
+	{
+		let this = self.clone();
+
+		thread::spawn(move || {
+			let fail = |position, e: KinesisError| {
+				eprintln!("Stopped reading shard {}: {}", shard_id, e);
+				let _ = done.send(Reader::Failed(shard_id.clone(), position));
+			};
+			let mut it = None;
+			let mut empty = 0;
+			let mut expired = 0;
+			let mut reported = Duration::default();
+
+			loop {
+				if stop.load(Ordering::SeqCst) {
+					return;
+				}
+				let current = match it.take() {
+					Some(current) => current,
+					None => match this.get_shard_iterator(shard_id.clone(), &position) {
+						Ok(current) => current,
+						Err(e) => return fail(position, e),
+					},
+				};
+				let polled = Instant::now();
+				let rec = match this.get_records(&current) {
+					Ok(rec) => rec,
+					Err(KinesisError::ExpiredIterator(_)) if expired + 1 < this.retry.attempts => {
+						expired += 1;
+						thread::sleep(this.retry.backoff(expired));
+						continue;
+					}
+					Err(e) => return fail(position, e),
+				};
+				expired = 0;
+
+				// A closed shard has no next iterator once read to its end
+				it = rec.next_shard_iterator;
+				empty = if rec.records.is_empty() { empty + 1 } else { 0 };
+				let wait = this.polling.wait(rec.millis_behind_latest, empty);
+				if let Some(last) = rec.records.last() {
+					position = StartPosition::AfterSequenceNumber(last.sequence_number.clone());
+				}
+
+				let mut stalled = Duration::default();
+				for r in rec.records.into_iter().flat_map(user_records) {
+					match send_waiting(&s, wrap(&shard_id, r), &stop) {
+						Some(waited) => stalled += waited,
+						None if stop.load(Ordering::SeqCst) => return,
+						None => {
+							// Nobody is reading this stream anymore
+							let _ = done.send(Reader::Stopped);
+							return;
+						}
+					}
+				}
+				if stalled > Duration::default() {
+					let total = this.metrics.stall(&shard_id, stalled);
+					if total - reported >= STALL_REPORT {
+						eprintln!(
+							"Reading shard {} of {} waited {}s so far for its records to be taken",
+							shard_id,
+							this.stream,
+							total.as_secs()
+						);
+						reported = total;
+					}
+				}
+
+				if it.is_none() {
+					break;
+				}
+				thread::sleep(wait.checked_sub(polled.elapsed()).unwrap_or_default());
+			}
+
+			let _ = done.send(Reader::Closed(shard_id));
+		})
 	}
 
-	pub fn get_records_stream(
+	/// Reads every shard of the stream, a shard created by a split or a merge
+	/// only once its parents were read to their end so the records of a
+	/// partition key keep their order. `start` gives the position of each
+	/// shard, telling whether it was listed at the start, or `None` to skip
+	/// it. With `leases` only the shards this worker holds a lease on are
+	/// read. Once the stream is stopped or nobody receives the records
+	/// anymore its thread stops the readers, waits for them and hands the
+	/// leases back. A shard whose reader failed is read again a while later
+	/// from where it stopped, and a shard `rewinds` names is read again
+	/// from the sequence number given along.
+	fn read_stream<T, F, P>(
 		self,
-		shard_id: Option<&str>,
-		starting_sequence_number: Option<&str>,
-	) -> crossbeam::Receiver<Record> {
-		let (s, r) = unbounded();
-
-		let (shards, iterator_type) = if shard_id.is_some() {
-			let shard = Shard {
-				shard_id: shard_id.unwrap().to_owned(),
-				..Default::default()
-			};
-			(vec![shard], s!("AT_SEQUENCE_NUMBER"))
-		} else {
-			(
-				self.list_shards()
-					.sync()
-					.expect("No shards founds for this stream")
-					.shards
-					.expect("List of shards not available")
-					.clone(),
-				s!("TRIM_HORIZON"),
-			)
-		};
+		shards: Vec<Shard>,
+		mut start: P,
+		mut leases: Option<Leases>,
+		rewinds: crossbeam::Receiver<(String, String)>,
+		wrap: F,
+	) -> RecordStream<T>
+	where
+		T: Send + 'static,
+		F: Fn(&str, UserRecord) -> T + Send + Sync + 'static,
+		P: FnMut(&Shard, bool) -> Option<StartPosition> + Send + 'static,
+	{
+		let (s, records) = bounded(self.capacity);
+		let stop = Arc::new(AtomicBool::new(false));
+		let flag = stop.clone();
+		let wrap = Arc::new(wrap);
 
-		for shard in shards {
-			let this = self.clone();
-			let s = s.clone();
-			let starting_sequence_number = match starting_sequence_number {
-				Some(s) => Some(s.to_string().clone()),
-				None => None,
+		let thread = thread::spawn(move || {
+			let stop = flag;
+			let (done, finished) = unbounded();
+			let mut graph = ShardGraph::new(shards);
+			// Shards listed from the start, as opposed to those created since
+			let initial: HashSet<String> =
+				graph.shards.iter().map(|s| s.shard_id.clone()).collect();
+			let mut running: HashMap<String, Arc<AtomicBool>> = HashMap::new();
+			let mut threads: Vec<JoinHandle<()>> = Vec::new();
+			let mut failed: HashMap<String, (StartPosition, Instant)> = HashMap::new();
+			let mut balanced: Option<Instant> = None;
+			let update = |graph: &mut ShardGraph| match self.list_shards() {
+				Ok(shards) => graph.update(shards),
+				Err(e) => eprintln!("Cannot list the shards of {}: {}", self.stream, e),
+			};
+			let read = |threads: &mut Vec<JoinHandle<()>>, shard_id: &str, position| {
+				let flag = Arc::new(AtomicBool::new(false));
+				threads.retain(|t| !t.is_finished());
+				threads.push(self.read_shard(
+					s!(shard_id),
+					position,
+					s.clone(),
+					done.clone(),
+					flag.clone(),
+					wrap.clone(),
+				));
+				flag
 			};
-			let iterator_type = iterator_type.clone();
 
-			thread::spawn(move || {
-				let mut it = this.get_shard_iterator(
-					shard.shard_id,
-					iterator_type,
-					starting_sequence_number,
-				);
-				loop {
-					let rec = this.get_records(&it);
-
-					it = rec
-						.next_shard_iterator
-						.expect("Next shard iterator not found")
-						.clone();
-					let r_len = rec.records.len();
-
-					for r in rec.records {
-						s.send(r).expect("Couldn't sent the log to the channel");
+			while !stop.load(Ordering::SeqCst) {
+				let readable = graph.readable();
+				let (acquired, lost) = match &mut leases {
+					None => (
+						readable
+							.into_iter()
+							.filter(|id| !running.contains_key(id) && !failed.contains_key(id))
+							.collect(),
+						vec![],
+					),
+					Some(_) if balanced.is_some_and(|b| b.elapsed() < LEASE_TTL / 3) => {
+						(vec![], vec![])
+					}
+					Some(leases) => {
+						balanced = Some(Instant::now());
+						match leases.balance(&readable) {
+							Ok(balance) => {
+								let unknown = balance
+									.finished
+									.iter()
+									.any(|id| !graph.drained.contains(id));
+								for shard_id in balance.finished.iter() {
+									graph.drained(shard_id);
+								}
+								if unknown {
+									// Children of shards drained by other workers
+									update(&mut graph);
+								}
+								(balance.acquired, balance.lost)
+							}
+							Err(e) => {
+								eprintln!("Cannot balance the leases of {}: {}", self.stream, e);
+								(vec![], vec![])
+							}
+						}
 					}
+				};
 
-					if r_len == 0 {
-						thread::sleep(Duration::from_millis(2000));
-					} else {
-						thread::sleep(Duration::from_millis(1000));
+				for shard_id in lost {
+					failed.remove(&shard_id);
+					if let Some(flag) = running.remove(&shard_id) {
+						flag.store(true, Ordering::SeqCst);
 					}
 				}
-			});
+				for (shard_id, sequence_number) in rewinds.try_iter() {
+					// Records the outputs failed, unless the shard was lost
+					let stopped = running
+						.remove(&shard_id)
+						.map(|flag| flag.store(true, Ordering::SeqCst));
+					if stopped.is_some() || failed.contains_key(&shard_id) {
+						let position = StartPosition::AtSequenceNumber(sequence_number);
+						failed.insert(shard_id, (position, Instant::now()));
+					}
+				}
+				let again: Vec<String> = failed
+					.iter()
+					.filter(|(_, (_, at))| at.elapsed() >= RESTART_AFTER)
+					.map(|(shard_id, _)| shard_id.clone())
+					.collect();
+				for shard_id in again {
+					let (position, _) = failed.remove(&shard_id).expect("Shard failed");
+					running.insert(shard_id.clone(), read(&mut threads, &shard_id, position));
+				}
+				let mut skipped = false;
+				for shard_id in acquired {
+					let shard = graph.get(&shard_id).cloned().expect("Shard listed");
+					match start(&shard, initial.contains(&shard_id)) {
+						Some(position) => {
+							running
+								.insert(shard_id.clone(), read(&mut threads, &shard_id, position));
+						}
+						None => {
+							graph.drained(&shard_id);
+							skipped = true;
+						}
+					}
+				}
+
+				if leases.is_none() && running.is_empty() && failed.is_empty() {
+					if skipped {
+						continue;
+					}
+					// Every shard was read to its end, the stream is gone
+					break;
+				}
+				match finished.recv_timeout(Duration::from_secs(1)) {
+					Ok(Reader::Closed(shard_id)) => {
+						running.remove(&shard_id);
+						graph.drained(&shard_id);
+						if let Some(leases) = &mut leases {
+							if let Err(e) = leases.finish(&shard_id) {
+								eprintln!("Cannot mark {} as read to its end: {}", shard_id, e);
+							}
+							balanced = None;
+						}
+						update(&mut graph);
+					}
+					Ok(Reader::Failed(shard_id, position)) => {
+						running.remove(&shard_id);
+						failed.insert(shard_id, (position, Instant::now()));
+					}
+					Ok(Reader::Stopped) => break,
+					Err(_) => {}
+				}
+			}
+
+			for flag in running.values() {
+				flag.store(true, Ordering::SeqCst);
+			}
+			for thread in threads {
+				if thread.join().is_err() {
+					eprintln!("A reader of {} panicked", self.stream);
+				}
+			}
+			leases
+		});
+
+		RecordStream {
+			records,
+			stop,
+			thread: Some(thread),
+			leases: None,
 		}
+	}
+
+	/// Reads the shards of the stream `start` tells from where it tells,
+	/// records aggregated by the KPL come unpacked. Only listing the shards
+	/// fails, the records of a single shard end once its reader fails.
+	pub fn get_records_stream(
+		self,
+		start: Start,
+	) -> Result<RecordStream<UserRecord>, KinesisError> {
+		let (shard_id, position) = match start {
+			Start::Shard(shard_id, position) => (shard_id, position),
+			start => {
+				let shards = self.list_shards()?;
+				return Ok(self.read_stream(
+					shards,
+					move |shard, initial| start.position(shard, initial),
+					None,
+					never(),
+					|_, r| r,
+				));
+			}
+		};
+
+		let (s, records) = bounded(self.capacity);
+		let stop = Arc::new(AtomicBool::new(false));
+		let wrap = Arc::new(|_: &str, r| r);
+		let reader = self.read_shard(shard_id, position, s, unbounded().0, stop.clone(), wrap);
+		// A single shard is read without leases
+		let thread = thread::spawn(move || {
+			if let Err(e) = reader.join() {
+				panic::resume_unwind(e);
+			}
+			None
+		});
 
-		r
+		Ok(RecordStream {
+			records,
+			stop,
+			thread: Some(thread),
+			leases: None,
+		})
 	}
 
-	pub fn put_records_stream<T: 'static>(self) -> crossbeam::Sender<T>
+	/// Reads every shard of the stream from right after the sequence number
+	/// `after` gives it, or from `start` when it has none. Records come with
+	/// the id of their shard. See `read_stream` for `leases` and `rewinds`.
+	pub fn resume_records_stream<A>(
+		self,
+		mut after: A,
+		start: StartPosition,
+		leases: Option<Leases>,
+		rewinds: crossbeam::Receiver<(String, String)>,
+	) -> Result<RecordStream<(String, UserRecord)>, KinesisError>
 	where
-		T: serde::Serialize + Send,
+		A: FnMut(&str) -> Option<String> + Send + 'static,
 	{
-		let (s, r) = unbounded();
+		let shards = self.list_shards()?;
+		Ok(self.read_stream(
+			shards,
+			move |shard, initial| match after(&shard.shard_id) {
+				Some(sequence_number) => Some(StartPosition::AfterSequenceNumber(sequence_number)),
+				None if initial => start.of(shard),
+				None => Some(StartPosition::TrimHorizon),
+			},
+			leases,
+			rewinds,
+			|shard_id, r| (s!(shard_id), r),
+		))
+	}
 
-		thread::spawn(move || {
-			let mut data = Vec::new();
+	/// Records of a shard from `position` as a stream polled on the task
+	/// of the caller, see `ShardStream`.
+	pub fn shard_stream(self, shard_id: String, position: StartPosition) -> ShardStream {
+		ShardStream::new(self, shard_id, position)
+	}
 
-			loop {
-				let item = r.recv().unwrap();
-				data.push(item);
+	/// Records of the shards `start` tells from where it tells as a stream
+	/// polled on the task of the caller, see `MergedStream`.
+	pub fn records_stream(self, start: Start) -> MergedStream {
+		MergedStream::new(self, start)
+	}
 
-				if data.len() == 500 {
-					let cop = data;
-					self.put_records(self.create_batch_from(cop));
-					data = Vec::new();
-				}
+	/// Sink putting the records sent to it in batches on the task of the
+	/// caller, see `PutSink`.
+	pub fn put_sink<T: serde::Serialize>(self, strategy: PartitionKeyStrategy) -> PutSink<T> {
+		PutSink::new(self, strategy)
+	}
+
+	/// Puts the records sent to the producer in batches, see `Producer`.
+	/// Records still refused after the retries go to the dead letter
+	/// callback, if any, besides being reported.
+	pub fn put_records_stream<T>(
+		self,
+		linger: Duration,
+		strategy: PartitionKeyStrategy,
+	) -> Producer<T>
+	where
+		T: serde::Serialize + Send + 'static,
+	{
+		Producer::start(self, linger, strategy)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{
+		certificates, put_with_retry, send_waiting, ClientOptions, KinesisError, KinesisHandler,
+		PartitionKeyStrategy, Polling, RecordStream, RetryPolicy, ShardGraph, StartPosition,
+	};
+	use crossbeam::channel::bounded;
+	use rusoto_core::RusotoError;
+	use rusoto_kinesis::{
+		GetRecordsError, PutRecordsOutput, PutRecordsRequestEntry, PutRecordsResultEntry,
+		SequenceNumberRange, Shard,
+	};
+	use serde_json::json;
+	use std::{
+		fs,
+		sync::{
+			atomic::{AtomicBool, Ordering},
+			Arc,
+		},
+		thread,
+		time::Duration,
+	};
+
+	fn shard(id: &str, parent: Option<&str>, adjacent: Option<&str>) -> Shard {
+		Shard {
+			shard_id: s!(id),
+			parent_shard_id: parent.map(String::from),
+			adjacent_parent_shard_id: adjacent.map(String::from),
+			..Default::default()
+		}
+	}
+
+	#[test]
+	fn reads_children_once_their_parents_are_drained() {
+		// 0 and 1 were merged into 2, which was split into 3 and 4. The parent
+		// of 0 is past the retention period.
+		let mut graph = ShardGraph::new(vec![
+			shard("0", Some("expired"), None),
+			shard("1", None, None),
+			shard("2", Some("0"), Some("1")),
+		]);
+
+		assert_eq!(vec!["0", "1"], graph.readable());
+
+		graph.drained("0");
+		assert_eq!(vec!["1"], graph.readable());
+		graph.drained("1");
+		assert_eq!(vec!["2"], graph.readable());
+
+		graph.drained("2");
+		graph.update(vec![
+			shard("2", Some("0"), Some("1")),
+			shard("3", Some("2"), None),
+			shard("4", Some("2"), None),
+		]);
+		assert_eq!(vec!["3", "4"], graph.readable());
+	}
+
+	#[test]
+	fn parses_start_positions() {
+		let parse = |text: &str| text.parse::<StartPosition>();
+
+		assert_eq!(Ok(StartPosition::Latest), parse("latest"));
+		assert_eq!(
+			Ok(StartPosition::AfterSequenceNumber(s!("4960"))),
+			parse("after_sequence_number:4960")
+		);
+		assert_eq!(
+			Ok(StartPosition::AtTimestamp(1561939200.5)),
+			parse("at_timestamp:1561939200.5")
+		);
+		assert_eq!(
+			"at_sequence_number:4960",
+			StartPosition::AtSequenceNumber(s!("4960")).to_string()
+		);
+		assert!(parse("at_timestamp:09:00").is_err());
+		assert!(parse("at_sequence_number:").is_err());
+		assert!(parse("latest:1").is_err());
+
+		// Closed shards have nothing new
+		let mut closed = shard("0", None, None);
+		closed.sequence_number_range = SequenceNumberRange {
+			starting_sequence_number: s!("1"),
+			ending_sequence_number: Some(s!("9")),
+		};
+		assert_eq!(None, StartPosition::Latest.of(&closed));
+		assert_eq!(
+			Some(StartPosition::TrimHorizon),
+			StartPosition::TrimHorizon.of(&closed)
+		);
+	}
+
+	#[test]
+	fn puts_refused_records_again() {
+		let retry = RetryPolicy {
+			attempts: 3,
+			base: Duration::from_millis(1),
+			max: Duration::from_millis(2),
+		};
+		let entries: Vec<PutRecordsRequestEntry> = ["a", "b", "c"]
+			.iter()
+			.map(|key| PutRecordsRequestEntry {
+				partition_key: key.to_string(),
+				..Default::default()
+			})
+			.collect();
+		let mut calls: Vec<Vec<String>> = vec![];
+
+		// `b` is always throttled, `c` only the first time
+		let failed = put_with_retry(entries, &retry, |records| {
+			let keys: Vec<String> = records.iter().map(|r| r.partition_key.clone()).collect();
+			let refused = |key: &str| key == "b" || (key == "c" && calls.is_empty());
+			let results: Vec<PutRecordsResultEntry> = keys
+				.iter()
+				.map(|key| PutRecordsResultEntry {
+					error_code: if refused(key) {
+						Some(s!("ProvisionedThroughputExceededException"))
+					} else {
+						None
+					},
+					..Default::default()
+				})
+				.collect();
+			calls.push(keys);
+
+			Ok(PutRecordsOutput {
+				failed_record_count: Some(
+					results.iter().filter(|r| r.error_code.is_some()).count() as i64,
+				),
+				records: results,
+				..Default::default()
+			})
+		})
+		.unwrap();
+
+		assert_eq!(vec![vec!["a", "b", "c"], vec!["b", "c"], vec!["b"]], calls);
+		assert_eq!(1, failed.len());
+		assert_eq!("b", failed[0].entry.partition_key);
+		assert_eq!(
+			"ProvisionedThroughputExceededException",
+			failed[0].error_code
+		);
+
+		for attempt in 1..40 {
+			assert!(retry.backoff(attempt) <= retry.max);
+		}
+	}
+
+	#[test]
+	fn polls_faster_while_behind_and_slower_while_idle() {
+		let polling = Polling::default();
+		let waits: Vec<u128> = [
+			(None, 0),
+			(Some(0), 1),
+			(Some(0), 2),
+			(Some(0), 3),
+			(Some(0), 9),
+		]
+		.iter()
+		.map(|(behind, empty)| polling.wait(*behind, *empty).as_millis())
+		.collect();
+		assert_eq!(vec![200, 400, 800, 1600, 2000], waits);
+		assert_eq!(200, polling.wait(Some(86_400_000), 5).as_millis());
+
+		// The shard limit holds whatever the policy says
+		let eager = Polling {
+			min: Duration::from_millis(10),
+			max: Duration::from_millis(50),
+			..Polling::default()
+		};
+		assert_eq!(200, eager.wait(Some(1_000), 0).as_millis());
+		assert_eq!(200, eager.wait(Some(0), 4).as_millis());
+	}
+
+	#[test]
+	fn waits_for_room_in_the_channel() {
+		let (s, r) = bounded(1);
+		let stop = AtomicBool::new(false);
+		assert_eq!(Some(Duration::default()), send_waiting(&s, 1, &stop));
+
+		let taker = thread::spawn(move || {
+			thread::sleep(Duration::from_millis(50));
+			r.recv().unwrap();
+			r
+		});
+		let stalled = send_waiting(&s, 2, &stop).expect("Sent once there is room");
+		assert!(stalled >= Duration::from_millis(40));
+
+		stop.store(true, Ordering::SeqCst);
+		assert_eq!(None, send_waiting(&s, 3, &stop));
+		drop(taker.join().unwrap());
+		assert_eq!(None, send_waiting(&s, 4, &AtomicBool::new(false)));
+	}
+
+	#[test]
+	fn shuts_down_once_readers_stopped_and_returns_what_they_sent() {
+		let (s, records) = bounded(3);
+		let stop = Arc::new(AtomicBool::new(false));
+		let flag = stop.clone();
+		let reader = thread::spawn(move || {
+			let mut i = 0;
+			while send_waiting(&s, i, &flag).is_some() {
+				i += 1;
 			}
+			None
 		});
+		let mut stream = RecordStream {
+			records,
+			stop,
+			thread: Some(reader),
+			leases: None,
+		};
+
+		assert_eq!(Ok(0), stream.records().recv());
+		thread::sleep(Duration::from_millis(50));
+		assert_eq!(vec![1, 2, 3], stream.shutdown());
+		assert!(stream.records().recv().is_err());
+		assert!(stream.shutdown().is_empty());
+	}
+
+	#[test]
+	fn trusts_every_certificate_of_a_bundle() {
+		let path = concat!(env!("CARGO_MANIFEST_DIR"), "/mycertfile.pem");
+		let pem = fs::read_to_string(path).unwrap();
+		assert_eq!(2, certificates(&format!("{}\n{}", pem, pem)).unwrap().len());
+		assert!(
+			certificates("-----BEGIN CERTIFICATE-----\nnope\n-----END CERTIFICATE-----").is_err()
+		);
+
+		let handler = |ca_bundle: &str| {
+			let options = ClientOptions {
+				ca_bundle: Some(s!(ca_bundle)),
+				..ClientOptions::default()
+			};
+			KinesisHandler::new_with_options(
+				s!("test"),
+				None,
+				Some("https://localhost:4568"),
+				&options,
+			)
+		};
+		assert!(handler(path).is_ok());
+		match handler("missing.pem") {
+			Err(KinesisError::Invalid(e)) => assert!(e.starts_with("missing.pem: ")),
+			_ => panic!("Expected an invalid CA bundle"),
+		}
+	}
+
+	#[test]
+	fn retries_calls_only_while_they_may_succeed() {
+		let retry = RetryPolicy {
+			attempts: 3,
+			base: Duration::from_millis(1),
+			max: Duration::from_millis(2),
+		};
+		let throttled: RusotoError<GetRecordsError> = RusotoError::Service(
+			GetRecordsError::ProvisionedThroughputExceeded(s!("slow down")),
+		);
+		let error = KinesisError::from(throttled);
+		assert!(error.is_retryable());
+		assert_eq!("throttled: slow down", error.to_string());
+
+		let mut calls = 0;
+		let result: Result<(), _> = retry.call(|| {
+			calls += 1;
+			Err(KinesisError::Transport(s!("connection reset")))
+		});
+		assert!(matches!(result, Err(KinesisError::Transport(_))));
+		assert_eq!(3, calls);
+
+		// Expired iterators are refreshed by the reader, not retried as they are
+		let mut calls = 0;
+		let result: Result<(), _> = retry.call(|| {
+			calls += 1;
+			Err(RusotoError::Service(GetRecordsError::ExpiredIterator(s!(""))).into())
+		});
+		assert!(matches!(result, Err(KinesisError::ExpiredIterator(_))));
+		assert_eq!(1, calls);
+
+		let mut calls = 0;
+		let result = retry.call(|| {
+			calls += 1;
+			if calls < 2 {
+				Err(KinesisError::Throttled(s!("")))
+			} else {
+				Ok(calls)
+			}
+		});
+		assert_eq!(2, result.unwrap());
+	}
+
+	#[test]
+	fn picks_partition_keys() {
+		let record = json!({"metadata": {"tenant": "acme", "id": 42}});
+		let data = record.to_string().into_bytes();
+		let keys = |strategy: &str| {
+			let strategy: PartitionKeyStrategy = strategy.parse().unwrap();
+			strategy.keys(&data, Some(&record))
+		};
+
+		assert_eq!((s!("acme"), None), keys("field:metadata.tenant"));
+		assert_eq!((s!("42"), None), keys("field:metadata.id"));
+		assert_ne!(
+			keys("field:metadata.missing"),
+			keys("field:metadata.missing")
+		);
+		assert_eq!(keys("hash"), keys("hash"));
+		assert_eq!(32, keys("hash").0.len());
+		assert_eq!(
+			(
+				s!("85070591730234615865843651857942052864"),
+				Some(s!("85070591730234615865843651857942052864"))
+			),
+			keys("explicit_hash_key:85070591730234615865843651857942052864")
+		);
 
-		s
+		assert!("field:metadata.".parse::<PartitionKeyStrategy>().is_err());
+		assert!("explicit_hash_key:tenant"
+			.parse::<PartitionKeyStrategy>()
+			.is_err());
+		assert!("tenant".parse::<PartitionKeyStrategy>().is_err());
 	}
 }
@@ -0,0 +1,324 @@
+use bytes::Bytes;
+use rusoto_kinesis::Record;
+
+/// First bytes of a record aggregated by the Kinesis Producer Library,
+/// followed by an `AggregatedRecord` protobuf message and its MD5.
+pub const MAGIC: [u8; 4] = [0xF3, 0x89, 0x9A, 0xC2];
+
+const DIGEST: usize = 16;
+
+/// A record as its producer put it: a Kinesis record, or one of the records
+/// an aggregated record holds.
+#[derive(Debug, Clone, PartialEq)]
+pub struct UserRecord {
+	pub data: Bytes,
+	pub partition_key: String,
+	pub explicit_hash_key: Option<String>,
+	/// Sequence number of the Kinesis record holding it.
+	pub sequence_number: String,
+	/// Position in the aggregated record holding it, `None` when the record
+	/// was not aggregated.
+	pub sub_sequence_number: Option<u64>,
+	pub approximate_arrival_timestamp: Option<f64>,
+}
+
+impl UserRecord {
+	/// Sequence number and sub-sequence number, like `4960:2`.
+	pub fn id(&self) -> String {
+		match self.sub_sequence_number {
+			Some(sub) => format!("{}:{}", self.sequence_number, sub),
+			None => self.sequence_number.clone(),
+		}
+	}
+}
+
+fn put_varint(out: &mut Vec<u8>, mut value: u64) {
+	while value >= 0x80 {
+		out.push(value as u8 | 0x80);
+		value >>= 7;
+	}
+	out.push(value as u8);
+}
+
+fn put_uint(out: &mut Vec<u8>, field: u64, value: u64) {
+	put_varint(out, field << 3);
+	put_varint(out, value);
+}
+
+fn put_bytes(out: &mut Vec<u8>, field: u64, bytes: &[u8]) {
+	put_varint(out, field << 3 | 2);
+	put_varint(out, bytes.len() as u64);
+	out.extend_from_slice(bytes);
+}
+
+/// Reads the fields of a protobuf message, `None` once it is malformed.
+struct Fields<'a> {
+	data: &'a [u8],
+}
+
+enum Field<'a> {
+	Uint(u64),
+	Bytes(&'a [u8]),
+}
+
+impl<'a> Fields<'a> {
+	fn varint(&mut self) -> Option<u64> {
+		let mut value = 0;
+		for (i, byte) in self.data.iter().enumerate().take(10) {
+			value |= u64::from(byte & 0x7F) << (7 * i);
+			if byte & 0x80 == 0 {
+				self.data = &self.data[i + 1..];
+				return Some(value);
+			}
+		}
+		None
+	}
+
+	fn next(&mut self) -> Option<Option<(u64, Field<'a>)>> {
+		if self.data.is_empty() {
+			return Some(None);
+		}
+
+		let key = self.varint()?;
+		let field = match key & 7 {
+			0 => Field::Uint(self.varint()?),
+			2 => {
+				let len = self.varint()? as usize;
+				if len > self.data.len() {
+					return None;
+				}
+				let (bytes, rest) = self.data.split_at(len);
+				self.data = rest;
+				Field::Bytes(bytes)
+			}
+			_ => return None,
+		};
+
+		Some(Some((key >> 3, field)))
+	}
+}
+
+/// Records of an aggregated record as `(partition key, explicit hash key,
+/// data)`, `None` when `data` is not one or is corrupted.
+pub fn deaggregate(data: &[u8]) -> Option<Vec<(String, Option<String>, Bytes)>> {
+	if data.len() < MAGIC.len() + DIGEST || data[..MAGIC.len()] != MAGIC {
+		return None;
+	}
+	let (body, digest) = data[MAGIC.len()..].split_at(data.len() - MAGIC.len() - DIGEST);
+	if md5::compute(body).0 != digest {
+		return None;
+	}
+
+	let text = |bytes: &[u8]| String::from_utf8(bytes.to_vec()).ok();
+	let mut partition_keys = Vec::new();
+	let mut hash_keys = Vec::new();
+	let mut records = Vec::new();
+	let mut fields = Fields { data: body };
+	while let Some((number, field)) = fields.next()? {
+		match (number, field) {
+			(1, Field::Bytes(key)) => partition_keys.push(text(key)?),
+			(2, Field::Bytes(key)) => hash_keys.push(text(key)?),
+			(3, Field::Bytes(record)) => records.push(record),
+			_ => {}
+		}
+	}
+
+	records
+		.into_iter()
+		.map(|record| {
+			let (mut partition_key, mut hash_key, mut data) = (None, None, None);
+			let mut fields = Fields { data: record };
+			while let Some((number, field)) = fields.next()? {
+				match (number, field) {
+					(1, Field::Uint(i)) => partition_key = partition_keys.get(i as usize),
+					(2, Field::Uint(i)) => hash_key = Some(hash_keys.get(i as usize)?),
+					(3, Field::Bytes(bytes)) => data = Some(Bytes::from(bytes)),
+					// Tags are not used
+					_ => {}
+				}
+			}
+			Some((partition_key?.clone(), hash_key.cloned(), data?))
+		})
+		.collect()
+}
+
+/// Records put by the producer of `record`, several if it aggregated them.
+pub fn user_records(record: Record) -> Vec<UserRecord> {
+	let user = |data, partition_key, explicit_hash_key, sub_sequence_number| UserRecord {
+		data,
+		partition_key,
+		explicit_hash_key,
+		sequence_number: record.sequence_number.clone(),
+		sub_sequence_number,
+		approximate_arrival_timestamp: record.approximate_arrival_timestamp,
+	};
+
+	match deaggregate(record.data.as_ref()) {
+		Some(records) => records
+			.into_iter()
+			.enumerate()
+			.map(|(i, (partition_key, hash_key, data))| {
+				user(data, partition_key, hash_key, Some(i as u64))
+			})
+			.collect(),
+		None => vec![user(
+			record.data.clone(),
+			record.partition_key.clone(),
+			None,
+			None,
+		)],
+	}
+}
+
+/// Records packed into one Kinesis record the way the Kinesis Producer
+/// Library does.
+#[derive(Default)]
+pub struct Aggregate {
+	partition_keys: Vec<String>,
+	hash_keys: Vec<String>,
+	records: Vec<(u64, Option<u64>, Bytes)>,
+	/// Size once encoded, at most.
+	size: usize,
+}
+
+impl Aggregate {
+	pub fn new() -> Self {
+		Aggregate {
+			size: MAGIC.len() + DIGEST,
+			..Default::default()
+		}
+	}
+
+	pub fn len(&self) -> usize {
+		self.records.len()
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.records.is_empty()
+	}
+
+	/// Size of the encoded aggregate once `data` is added, at most.
+	pub fn size_with(
+		&self,
+		partition_key: &str,
+		explicit_hash_key: Option<&str>,
+		data: &[u8],
+	) -> usize {
+		// Tags and lengths take at most 11 bytes for each field
+		let table = |table: &[String], key: &str| {
+			if table.iter().any(|k| k == key) {
+				0
+			} else {
+				key.len() + 11
+			}
+		};
+
+		self.size
+			+ table(&self.partition_keys, partition_key)
+			+ explicit_hash_key.map_or(0, |key| table(&self.hash_keys, key))
+			+ data.len()
+			+ 4 * 11
+	}
+
+	pub fn push(&mut self, partition_key: &str, explicit_hash_key: Option<&str>, data: Bytes) {
+		self.size = self.size_with(partition_key, explicit_hash_key, &data);
+
+		let index = |table: &mut Vec<String>, key: &str| match table.iter().position(|k| k == key) {
+			Some(i) => i as u64,
+			None => {
+				table.push(s!(key));
+				table.len() as u64 - 1
+			}
+		};
+		let partition_key = index(&mut self.partition_keys, partition_key);
+		let hash_key = explicit_hash_key.map(|key| index(&mut self.hash_keys, key));
+		self.records.push((partition_key, hash_key, data));
+	}
+
+	/// Partition key and explicit hash key the aggregate is put with, those
+	/// of its first record.
+	pub fn keys(&self) -> Option<(String, Option<String>)> {
+		let (partition_key, hash_key, _) = self.records.first()?;
+
+		Some((
+			self.partition_keys[*partition_key as usize].clone(),
+			hash_key.map(|i| self.hash_keys[i as usize].clone()),
+		))
+	}
+
+	pub fn encode(&self) -> Vec<u8> {
+		let mut body = Vec::with_capacity(self.size);
+		for key in self.partition_keys.iter() {
+			put_bytes(&mut body, 1, key.as_bytes());
+		}
+		for key in self.hash_keys.iter() {
+			put_bytes(&mut body, 2, key.as_bytes());
+		}
+		for (partition_key, hash_key, data) in self.records.iter() {
+			let mut record = Vec::with_capacity(data.len() + 16);
+			put_uint(&mut record, 1, *partition_key);
+			if let Some(hash_key) = hash_key {
+				put_uint(&mut record, 2, *hash_key);
+			}
+			put_bytes(&mut record, 3, data);
+			put_bytes(&mut body, 3, &record);
+		}
+
+		let mut out = Vec::with_capacity(body.len() + MAGIC.len() + DIGEST);
+		out.extend_from_slice(&MAGIC);
+		out.extend_from_slice(&body);
+		out.extend_from_slice(&md5::compute(&body).0);
+		out
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{deaggregate, user_records, Aggregate, MAGIC};
+	use bytes::Bytes;
+	use rusoto_kinesis::Record;
+
+	#[test]
+	fn aggregates_and_deaggregates() {
+		let mut aggregate = Aggregate::new();
+		aggregate.push("acme", None, Bytes::from("first"));
+		aggregate.push("other", Some("42"), Bytes::from(vec![0; 300]));
+		aggregate.push("acme", None, Bytes::from("third"));
+		let encoded = aggregate.encode();
+
+		assert_eq!(MAGIC, encoded[..4]);
+		assert!(encoded.len() <= aggregate.size);
+		assert_eq!(Some((s!("acme"), None)), aggregate.keys());
+
+		let records = user_records(Record {
+			data: Bytes::from(encoded.clone()),
+			partition_key: s!("acme"),
+			sequence_number: s!("4960"),
+			..Default::default()
+		});
+		let ids: Vec<String> = records.iter().map(|r| r.id()).collect();
+		assert_eq!(vec!["4960:0", "4960:1", "4960:2"], ids);
+		assert_eq!(Bytes::from("third"), records[2].data);
+		assert_eq!(
+			(s!("other"), Some(s!("42"))),
+			(
+				records[1].partition_key.clone(),
+				records[1].explicit_hash_key.clone()
+			)
+		);
+
+		// A corrupted aggregate is passed on as it is
+		let mut corrupted = encoded;
+		corrupted[10] ^= 1;
+		assert_eq!(None, deaggregate(&corrupted));
+		let records = user_records(Record {
+			data: Bytes::from(corrupted),
+			sequence_number: s!("4961"),
+			..Default::default()
+		});
+		assert_eq!(
+			vec![s!("4961")],
+			records.iter().map(|r| r.id()).collect::<Vec<_>>()
+		);
+	}
+}
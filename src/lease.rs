@@ -0,0 +1,587 @@
+use rand::RngCore;
+use road_postgres::PG;
+use std::{
+	collections::{BTreeSet, HashMap, HashSet},
+	env, fmt, process,
+	sync::Arc,
+	time::Duration,
+};
+
+/// Owner of the leases of shards read to their end, they are never taken
+/// again and their children can be read.
+pub const FINISHED: &str = "SHARD_END";
+
+/// How long a lease lasts without being renewed, workers renew theirs three
+/// times in that period.
+pub const LEASE_TTL: Duration = Duration::from_secs(10);
+
+#[derive(Debug)]
+pub enum LeaseError {
+	Redis(redis::RedisError),
+	Postgres(road_postgres::Error),
+}
+
+impl fmt::Display for LeaseError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			LeaseError::Redis(e) => write!(f, "redis: {}", e),
+			LeaseError::Postgres(e) => write!(f, "postgres: {}", e),
+		}
+	}
+}
+
+impl From<redis::RedisError> for LeaseError {
+	fn from(e: redis::RedisError) -> Self {
+		LeaseError::Redis(e)
+	}
+}
+
+impl From<road_postgres::Error> for LeaseError {
+	fn from(e: road_postgres::Error) -> Self {
+		LeaseError::Postgres(e)
+	}
+}
+
+/// Where the workers reading the same input record which of them reads each
+/// shard. Leases expire unless their owner renews them.
+pub trait LeaseStore: Send {
+	/// Owner of every lease that has not expired, by shard.
+	fn owners(&mut self) -> Result<HashMap<String, String>, LeaseError>;
+
+	/// Takes the lease of `shard_id` for `ttl` unless somebody else holds it,
+	/// renewing it if `owner` already does.
+	fn acquire(&mut self, shard_id: &str, owner: &str, ttl: Duration) -> Result<bool, LeaseError>;
+
+	/// Extends the lease if `owner` still holds it.
+	fn renew(&mut self, shard_id: &str, owner: &str, ttl: Duration) -> Result<bool, LeaseError>;
+
+	/// Takes the lease over from `from` if it still holds it.
+	fn steal(
+		&mut self,
+		shard_id: &str,
+		from: &str,
+		owner: &str,
+		ttl: Duration,
+	) -> Result<bool, LeaseError>;
+
+	/// Gives the lease up if `owner` holds it.
+	fn release(&mut self, shard_id: &str, owner: &str) -> Result<(), LeaseError>;
+
+	/// Marks the shard as read to its end, for good.
+	fn finish(&mut self, shard_id: &str) -> Result<(), LeaseError>;
+}
+
+/// Leases of an input in the hash `firehouse:leases:<input>`, each field a
+/// shard holding `<expiry in ms> <owner>`. Expiries use the clock of Redis
+/// so workers do not have to agree on the time.
+pub struct RedisLeases {
+	con: redis::Connection,
+	key: String,
+}
+
+impl RedisLeases {
+	pub fn new(con: redis::Connection, input: &str) -> Self {
+		RedisLeases {
+			con,
+			key: format!("firehouse:leases:{}", input),
+		}
+	}
+
+	/// Runs `body` after `PRELUDE` on the hash of the input, `ARGV[1]` being
+	/// the shard.
+	fn call<T: redis::FromRedisValue>(
+		&mut self,
+		body: &str,
+		shard_id: &str,
+		args: &[&str],
+	) -> Result<T, LeaseError> {
+		let script = redis::Script::new(&format!("{}{}", PRELUDE, body));
+		let mut invocation = script.key(&self.key);
+		invocation.arg(shard_id);
+		for arg in args {
+			invocation.arg(*arg);
+		}
+
+		Ok(invocation.invoke(&mut self.con)?)
+	}
+}
+
+/// Current time of Redis as `now`, `holder(shard)` is the owner of a lease
+/// that has not expired and `lease(owner, ttl)` the value of a new one. An
+/// expiry of 0 never comes.
+const PRELUDE: &str = "
+redis.replicate_commands()
+local time = redis.call('TIME')
+local now = time[1] * 1000 + math.floor(time[2] / 1000)
+local function holder(shard)
+	local lease = redis.call('HGET', KEYS[1], shard)
+	if not lease then
+		return nil
+	end
+	local expires, owner = string.match(lease, '^(%d+) (.*)$')
+	expires = tonumber(expires)
+	if expires ~= 0 and expires <= now then
+		return nil
+	end
+	return owner
+end
+local function lease(owner, ttl)
+	return string.format('%d %s', now + tonumber(ttl), owner)
+end
+";
+
+const OWNERS: &str = "
+local owners = {}
+local leases = redis.call('HGETALL', KEYS[1])
+for i = 1, #leases, 2 do
+	local owner = holder(leases[i])
+	if owner then
+		table.insert(owners, leases[i])
+		table.insert(owners, owner)
+	end
+end
+return owners";
+
+/// `ARGV[2]` takes the lease for `ARGV[3]` ms when free or already its own.
+const ACQUIRE: &str = "
+local owner = holder(ARGV[1])
+if owner == nil or owner == ARGV[2] then
+	redis.call('HSET', KEYS[1], ARGV[1], lease(ARGV[2], ARGV[3]))
+	return 1
+end
+return 0";
+
+const RENEW: &str = "
+if holder(ARGV[1]) == ARGV[2] then
+	redis.call('HSET', KEYS[1], ARGV[1], lease(ARGV[2], ARGV[3]))
+	return 1
+end
+return 0";
+
+/// `ARGV[3]` takes the lease over from `ARGV[2]` for `ARGV[4]` ms.
+const STEAL: &str = "
+if holder(ARGV[1]) == ARGV[2] then
+	redis.call('HSET', KEYS[1], ARGV[1], lease(ARGV[3], ARGV[4]))
+	return 1
+end
+return 0";
+
+const RELEASE: &str = "
+if holder(ARGV[1]) == ARGV[2] then
+	redis.call('HDEL', KEYS[1], ARGV[1])
+end
+return 0";
+
+impl LeaseStore for RedisLeases {
+	fn owners(&mut self) -> Result<HashMap<String, String>, LeaseError> {
+		self.call(OWNERS, "", &[])
+	}
+
+	fn acquire(&mut self, shard_id: &str, owner: &str, ttl: Duration) -> Result<bool, LeaseError> {
+		let ttl = ttl.as_millis().to_string();
+		let acquired: i64 = self.call(ACQUIRE, shard_id, &[owner, &ttl])?;
+
+		Ok(acquired == 1)
+	}
+
+	fn renew(&mut self, shard_id: &str, owner: &str, ttl: Duration) -> Result<bool, LeaseError> {
+		let ttl = ttl.as_millis().to_string();
+		let renewed: i64 = self.call(RENEW, shard_id, &[owner, &ttl])?;
+
+		Ok(renewed == 1)
+	}
+
+	fn steal(
+		&mut self,
+		shard_id: &str,
+		from: &str,
+		owner: &str,
+		ttl: Duration,
+	) -> Result<bool, LeaseError> {
+		let ttl = ttl.as_millis().to_string();
+		let stolen: i64 = self.call(STEAL, shard_id, &[from, owner, &ttl])?;
+
+		Ok(stolen == 1)
+	}
+
+	fn release(&mut self, shard_id: &str, owner: &str) -> Result<(), LeaseError> {
+		let _: i64 = self.call(RELEASE, shard_id, &[owner])?;
+
+		Ok(())
+	}
+
+	fn finish(&mut self, shard_id: &str) -> Result<(), LeaseError> {
+		let _: () = redis::cmd("HSET")
+			.arg(&self.key)
+			.arg(shard_id)
+			.arg(format!("0 {}", FINISHED))
+			.query(&mut self.con)?;
+
+		Ok(())
+	}
+}
+
+/// Leases of every input in the `firehouse_leases` table, created if
+/// needed. Expiries use the clock of the database so workers do not have to
+/// agree on the time.
+pub struct PostgresLeases {
+	pg: Arc<PG>,
+	input: String,
+}
+
+impl PostgresLeases {
+	pub fn new(pg: Arc<PG>, input: &str) -> Result<Self, LeaseError> {
+		pg.execute(
+			"CREATE TABLE IF NOT EXISTS firehouse_leases (
+				input TEXT NOT NULL,
+				shard_id TEXT NOT NULL,
+				owner TEXT NOT NULL,
+				expires_at TIMESTAMPTZ NOT NULL,
+				PRIMARY KEY (input, shard_id)
+			)",
+			&[],
+		)?;
+
+		Ok(PostgresLeases {
+			pg,
+			input: s!(input),
+		})
+	}
+
+	fn change(&self, statement: &str, shard_id: &str, vals: &[&str]) -> Result<bool, LeaseError> {
+		let mut params = vec![self.input.clone(), s!(shard_id)];
+		params.extend(vals.iter().map(|v| v.to_string()));
+
+		Ok(self.pg.execute(statement, &params)? == 1)
+	}
+}
+
+impl LeaseStore for PostgresLeases {
+	fn owners(&mut self) -> Result<HashMap<String, String>, LeaseError> {
+		let rows = self.pg.select(
+			"SELECT shard_id, owner FROM firehouse_leases
+			WHERE input = $1 AND expires_at > now()",
+			std::slice::from_ref(&self.input),
+		)?;
+
+		Ok(rows
+			.into_iter()
+			.map(|mut row| (row.swap_remove(0), row.swap_remove(0)))
+			.collect())
+	}
+
+	fn acquire(&mut self, shard_id: &str, owner: &str, ttl: Duration) -> Result<bool, LeaseError> {
+		self.change(
+			"INSERT INTO firehouse_leases (input, shard_id, owner, expires_at)
+			VALUES ($1, $2, $3, now() + ($4 || ' milliseconds')::interval)
+			ON CONFLICT (input, shard_id) DO UPDATE
+			SET owner = EXCLUDED.owner, expires_at = EXCLUDED.expires_at
+			WHERE firehouse_leases.owner = EXCLUDED.owner
+				OR firehouse_leases.expires_at <= now()",
+			shard_id,
+			&[owner, &ttl.as_millis().to_string()],
+		)
+	}
+
+	fn renew(&mut self, shard_id: &str, owner: &str, ttl: Duration) -> Result<bool, LeaseError> {
+		self.change(
+			"UPDATE firehouse_leases SET expires_at = now() + ($4 || ' milliseconds')::interval
+			WHERE input = $1 AND shard_id = $2 AND owner = $3 AND expires_at > now()",
+			shard_id,
+			&[owner, &ttl.as_millis().to_string()],
+		)
+	}
+
+	fn steal(
+		&mut self,
+		shard_id: &str,
+		from: &str,
+		owner: &str,
+		ttl: Duration,
+	) -> Result<bool, LeaseError> {
+		self.change(
+			"UPDATE firehouse_leases
+			SET owner = $4, expires_at = now() + ($5 || ' milliseconds')::interval
+			WHERE input = $1 AND shard_id = $2 AND owner = $3",
+			shard_id,
+			&[from, owner, &ttl.as_millis().to_string()],
+		)
+	}
+
+	fn release(&mut self, shard_id: &str, owner: &str) -> Result<(), LeaseError> {
+		self.change(
+			"DELETE FROM firehouse_leases WHERE input = $1 AND shard_id = $2 AND owner = $3",
+			shard_id,
+			&[owner],
+		)?;
+
+		Ok(())
+	}
+
+	fn finish(&mut self, shard_id: &str) -> Result<(), LeaseError> {
+		self.change(
+			"INSERT INTO firehouse_leases (input, shard_id, owner, expires_at)
+			VALUES ($1, $2, $3, 'infinity')
+			ON CONFLICT (input, shard_id) DO UPDATE
+			SET owner = EXCLUDED.owner, expires_at = EXCLUDED.expires_at",
+			shard_id,
+			&[FINISHED],
+		)?;
+
+		Ok(())
+	}
+}
+
+/// What a worker has to change after balancing its leases.
+#[derive(Debug, Default, PartialEq)]
+pub struct Balance {
+	/// Shards to start reading.
+	pub acquired: Vec<String>,
+	/// Shards to stop reading, their lease was lost or given to others.
+	pub lost: Vec<String>,
+	/// Shards somebody read to their end.
+	pub finished: Vec<String>,
+}
+
+/// Leases held by this worker. Every worker aims at an even share of the
+/// readable shards: it takes free and expired leases until it has its
+/// share, gives up the ones above it, and when nothing is free steals one
+/// lease per round from the worker holding the most.
+pub struct Leases {
+	store: Box<dyn LeaseStore>,
+	owner: String,
+	held: BTreeSet<String>,
+}
+
+impl Leases {
+	/// The worker is named after the host and the process.
+	pub fn new(store: Box<dyn LeaseStore>) -> Self {
+		let host = env::var("HOSTNAME").unwrap_or_else(|_| s!("firehouse"));
+		let owner = format!(
+			"{}-{}-{:08x}",
+			host,
+			process::id(),
+			rand::thread_rng().next_u32()
+		);
+
+		Leases::with_owner(store, &owner)
+	}
+
+	pub fn with_owner(store: Box<dyn LeaseStore>, owner: &str) -> Self {
+		Leases {
+			store,
+			owner: s!(owner),
+			held: BTreeSet::new(),
+		}
+	}
+
+	pub fn owner(&self) -> &str {
+		&self.owner
+	}
+
+	/// Renews the leases held and moves towards an even share of
+	/// `readable`, the shards whose parents were read to their end.
+	pub fn balance(&mut self, readable: &[String]) -> Result<Balance, LeaseError> {
+		let mut balance = Balance::default();
+
+		for shard_id in self.held.clone() {
+			if !self.store.renew(&shard_id, &self.owner, LEASE_TTL)? {
+				self.held.remove(&shard_id);
+				balance.lost.push(shard_id);
+			}
+		}
+
+		let owners = self.store.owners()?;
+		balance.finished = owners
+			.iter()
+			.filter(|(_, owner)| owner.as_str() == FINISHED)
+			.map(|(shard_id, _)| shard_id.clone())
+			.collect();
+		balance.finished.sort();
+
+		let readable: Vec<&String> = readable
+			.iter()
+			.filter(|shard_id| owners.get(*shard_id).map(String::as_str) != Some(FINISHED))
+			.collect();
+		let mut workers: HashSet<&str> = readable
+			.iter()
+			.filter_map(|shard_id| owners.get(*shard_id))
+			.map(String::as_str)
+			.collect();
+		workers.insert(&self.owner);
+		let share = readable.len().div_ceil(workers.len());
+
+		while self.held.len() > share {
+			let shard_id = self.held.iter().next_back().cloned().expect("Leases held");
+			self.store.release(&shard_id, &self.owner)?;
+			self.held.remove(&shard_id);
+			balance.lost.push(shard_id);
+		}
+
+		for shard_id in readable.iter().filter(|s| !owners.contains_key(**s)) {
+			if self.held.len() >= share {
+				break;
+			}
+			if self.store.acquire(shard_id, &self.owner, LEASE_TTL)? {
+				self.held.insert(shard_id.to_string());
+				balance.acquired.push(shard_id.to_string());
+			}
+		}
+
+		if self.held.len() < share {
+			let mut leases: HashMap<&str, Vec<&String>> = HashMap::new();
+			for shard_id in readable.iter() {
+				if let Some(owner) = owners.get(*shard_id) {
+					leases.entry(owner).or_default().push(shard_id);
+				}
+			}
+			let busiest = leases
+				.into_iter()
+				.filter(|(owner, _)| *owner != self.owner)
+				.max_by_key(|(owner, shards)| (shards.len(), *owner));
+
+			if let Some((from, shards)) = busiest {
+				let shard_id = shards.iter().max().expect("Workers hold leases");
+				if shards.len() > self.held.len() + 1
+					&& self.store.steal(shard_id, from, &self.owner, LEASE_TTL)?
+				{
+					self.held.insert(shard_id.to_string());
+					balance.acquired.push(shard_id.to_string());
+				}
+			}
+		}
+
+		Ok(balance)
+	}
+
+	/// Records that the shard was read to its end.
+	pub fn finish(&mut self, shard_id: &str) -> Result<(), LeaseError> {
+		self.held.remove(shard_id);
+		self.store.finish(shard_id)
+	}
+
+	/// Gives up every lease held, for another worker to take them right away.
+	pub fn release_all(&mut self) {
+		for shard_id in std::mem::take(&mut self.held) {
+			if let Err(e) = self.store.release(&shard_id, &self.owner) {
+				eprintln!("Cannot release the lease of {}: {}", shard_id, e);
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{Balance, LeaseError, LeaseStore, Leases, FINISHED};
+	use std::{
+		collections::HashMap,
+		sync::{Arc, Mutex},
+		time::Duration,
+	};
+
+	/// Leases shared by the workers of a test, expired by hand.
+	#[derive(Clone, Default)]
+	struct Memory(Arc<Mutex<HashMap<String, String>>>);
+
+	impl LeaseStore for Memory {
+		fn owners(&mut self) -> Result<HashMap<String, String>, LeaseError> {
+			Ok(self.0.lock().unwrap().clone())
+		}
+
+		fn acquire(
+			&mut self,
+			shard_id: &str,
+			owner: &str,
+			_: Duration,
+		) -> Result<bool, LeaseError> {
+			let mut leases = self.0.lock().unwrap();
+			let current = leases.entry(s!(shard_id)).or_insert_with(|| s!(owner));
+			Ok(current == owner)
+		}
+
+		fn renew(&mut self, shard_id: &str, owner: &str, _: Duration) -> Result<bool, LeaseError> {
+			Ok(self.0.lock().unwrap().get(shard_id).map(String::as_str) == Some(owner))
+		}
+
+		fn steal(
+			&mut self,
+			shard_id: &str,
+			from: &str,
+			owner: &str,
+			_: Duration,
+		) -> Result<bool, LeaseError> {
+			let mut leases = self.0.lock().unwrap();
+			match leases.get_mut(shard_id) {
+				Some(current) if current == from => {
+					*current = s!(owner);
+					Ok(true)
+				}
+				_ => Ok(false),
+			}
+		}
+
+		fn release(&mut self, shard_id: &str, owner: &str) -> Result<(), LeaseError> {
+			let mut leases = self.0.lock().unwrap();
+			if leases.get(shard_id).map(String::as_str) == Some(owner) {
+				leases.remove(shard_id);
+			}
+			Ok(())
+		}
+
+		fn finish(&mut self, shard_id: &str) -> Result<(), LeaseError> {
+			self.0.lock().unwrap().insert(s!(shard_id), s!(FINISHED));
+			Ok(())
+		}
+	}
+
+	fn shards(ids: &[&str]) -> Vec<String> {
+		ids.iter().map(|id| id.to_string()).collect()
+	}
+
+	#[test]
+	fn shares_the_shards_between_workers() {
+		let memory = Memory::default();
+		let mut a = Leases::with_owner(Box::new(memory.clone()), "a");
+		let mut b = Leases::with_owner(Box::new(memory.clone()), "b");
+		let readable = shards(&["0", "1", "2", "3"]);
+
+		assert_eq!(
+			shards(&["0", "1", "2", "3"]),
+			a.balance(&readable).unwrap().acquired
+		);
+
+		// B steals a lease, A notices and gives up the ones above its share
+		assert_eq!(shards(&["3"]), b.balance(&readable).unwrap().acquired);
+		assert_eq!(shards(&["3", "2"]), a.balance(&readable).unwrap().lost);
+		assert_eq!(shards(&["2"]), b.balance(&readable).unwrap().acquired);
+		assert_eq!(Balance::default(), a.balance(&readable).unwrap());
+		assert_eq!(Balance::default(), b.balance(&readable).unwrap());
+
+		// B finishes a shard, its child becomes readable and A takes it
+		b.finish("3").unwrap();
+		let readable = shards(&["0", "1", "2", "4"]);
+		let balance = a.balance(&readable).unwrap();
+		assert_eq!(shards(&["3"]), balance.finished);
+		assert!(balance.acquired.is_empty());
+		assert_eq!(shards(&["4"]), b.balance(&readable).unwrap().acquired);
+	}
+
+	#[test]
+	fn takes_over_the_shards_of_dead_workers() {
+		let memory = Memory::default();
+		let mut a = Leases::with_owner(Box::new(memory.clone()), "a");
+		let mut b = Leases::with_owner(Box::new(memory.clone()), "b");
+		let readable = shards(&["0", "1"]);
+
+		a.balance(&readable).unwrap();
+		b.balance(&readable).unwrap();
+		a.balance(&readable).unwrap();
+
+		// The leases of A expire
+		memory.0.lock().unwrap().retain(|_, owner| owner != "a");
+		assert_eq!(shards(&["0"]), b.balance(&readable).unwrap().acquired);
+
+		b.release_all();
+		assert!(memory.0.lock().unwrap().is_empty());
+	}
+}
@@ -0,0 +1,26 @@
+#[macro_use]
+extern crate serde_derive;
+extern crate amiquip;
+extern crate bytes;
+extern crate redis;
+extern crate reqwest;
+extern crate road_postgres;
+extern crate serde_yaml;
+
+#[macro_use]
+pub mod utils;
+pub mod checkpoint;
+pub mod config;
+pub mod connections;
+pub mod explain;
+pub mod filter;
+pub mod firehouse;
+pub mod kinesis;
+pub mod kpl;
+pub mod lease;
+pub mod pipeline;
+pub mod producer;
+pub mod reload;
+pub mod router;
+pub mod stream;
+pub mod transform;
@@ -0,0 +1,354 @@
+use crossbeam::channel::{bounded, Receiver, RecvTimeoutError, Sender, TrySendError};
+use std::{
+	collections::VecDeque,
+	fs::{self, File, OpenOptions},
+	io::{self, Read, Seek, SeekFrom, Write},
+	path::Path,
+	sync::{Arc, Mutex},
+	time::Duration,
+};
+
+use super::{Ack, Delivery, Message};
+use crate::config::FullPolicy;
+
+/// Drops are reported once every that many records.
+const DROP_REPORT: usize = 1000;
+
+fn write_field(out: &mut Vec<u8>, field: &[u8]) {
+	out.extend_from_slice(&(field.len() as u32).to_be_bytes());
+	out.extend_from_slice(field);
+}
+
+fn read_field<R: Read>(input: &mut R) -> io::Result<Vec<u8>> {
+	let mut len = [0; 4];
+	input.read_exact(&mut len)?;
+	let mut field = vec![0; u32::from_be_bytes(len) as usize];
+	input.read_exact(&mut field)?;
+
+	Ok(field)
+}
+
+fn read_message<R: Read>(input: &mut R) -> io::Result<Message> {
+	let mut text = || {
+		read_field(input).and_then(|f| {
+			String::from_utf8(f).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+		})
+	};
+	let (key, id, origin) = (text()?, text()?, text()?);
+	let data = read_field(input)?;
+
+	Ok(Message::new(data, &key, &id, &origin))
+}
+
+/// File holding the records of an output that did not fit in its buffer.
+/// Records are read back in order and the file is truncated once it has
+/// been read entirely. Their acks wait in memory, those of records lost
+/// with the file or the process fail.
+struct Spill {
+	file: File,
+	read: u64,
+	pending: usize,
+	/// Records left by a previous run, read first and without acks.
+	orphans: usize,
+	acks: VecDeque<Ack>,
+}
+
+impl Spill {
+	/// Records left by a previous run are kept and delivered first.
+	fn open(dir: &str, output: &str) -> io::Result<Self> {
+		fs::create_dir_all(dir)?;
+		let mut file = OpenOptions::new()
+			.read(true)
+			.write(true)
+			.create(true)
+			.truncate(false)
+			.open(Path::new(dir).join(format!("{}.spill", output)))?;
+
+		let len = file.metadata()?.len();
+		let mut pending = 0;
+		let mut input = io::BufReader::new(&mut file);
+		while input.stream_position()? < len {
+			read_message(&mut input)?;
+			pending += 1;
+		}
+
+		Ok(Spill {
+			file,
+			read: 0,
+			pending,
+			orphans: pending,
+			acks: VecDeque::new(),
+		})
+	}
+
+	/// Keeps `ack` once the message is written, `Err` hands it back.
+	fn push(&mut self, message: &Message, ack: Ack) -> Result<(), (io::Error, Ack)> {
+		match self.write(message) {
+			Ok(()) => {
+				self.acks.push_back(ack);
+				Ok(())
+			}
+			Err(e) => Err((e, ack)),
+		}
+	}
+
+	fn write(&mut self, message: &Message) -> io::Result<()> {
+		let mut record = Vec::with_capacity(message.data.len() + 64);
+		write_field(&mut record, message.key.as_bytes());
+		write_field(&mut record, message.id.as_bytes());
+		write_field(&mut record, message.origin.as_bytes());
+		write_field(&mut record, message.data.as_ref());
+
+		self.file.seek(SeekFrom::End(0))?;
+		self.file.write_all(&record)?;
+		self.pending += 1;
+
+		Ok(())
+	}
+
+	fn pop(&mut self) -> io::Result<Option<Delivery>> {
+		if self.pending == 0 {
+			return Ok(None);
+		}
+
+		self.file.seek(SeekFrom::Start(self.read))?;
+		let message = read_message(&mut self.file)?;
+		self.read = self.file.stream_position()?;
+		self.pending -= 1;
+		let ack = if self.orphans > 0 {
+			self.orphans -= 1;
+			Ack::none()
+		} else {
+			self.acks.pop_front().expect("Spilled record without ack")
+		};
+		if self.pending == 0 {
+			self.clear()?;
+		}
+
+		Ok(Some(Delivery::new(message, ack)))
+	}
+
+	/// Forgets every spilled record, their acks fail.
+	fn clear(&mut self) -> io::Result<()> {
+		self.file.set_len(0)?;
+		self.read = 0;
+		self.pending = 0;
+		self.orphans = 0;
+		self.acks.clear();
+
+		Ok(())
+	}
+}
+
+/// Queue of one output, see `buffer`.
+pub struct BufferSender {
+	output: String,
+	policy: FullPolicy,
+	sender: Sender<Delivery>,
+	/// For `DropOldest`, to take the oldest record out.
+	receiver: Receiver<Delivery>,
+	spill: Option<Arc<Mutex<Spill>>>,
+	dropped: usize,
+}
+
+impl BufferSender {
+	/// Queues a delivery, applying the policy of the output when its buffer
+	/// is full. Spilled records keep their ack until the output writes them,
+	/// dropped ones are acked as handled and counted.
+	pub fn send(&mut self, delivery: Delivery) {
+		if let Some(spill) = &self.spill {
+			let mut spill = spill.lock().expect("Spill file poisoned");
+			// Nothing skips the records already spilled
+			let delivery = if spill.pending > 0 {
+				delivery
+			} else {
+				match self.sender.try_send(delivery) {
+					Ok(()) => return,
+					Err(TrySendError::Full(delivery)) => delivery,
+					Err(TrySendError::Disconnected(_)) => panic!("Output thread stopped"),
+				}
+			};
+			let Delivery { message, ack } = delivery;
+			if let Err((e, ack)) = spill.push(&message, ack) {
+				drop(spill);
+				eprintln!(
+					"Output {} cannot spill, waiting instead: {}",
+					self.output, e
+				);
+				let delivery = Delivery::new(message, ack);
+				self.sender.send(delivery).expect("Output thread stopped");
+			}
+			return;
+		}
+
+		let mut delivery = match self.sender.try_send(delivery) {
+			Ok(()) => return,
+			Err(TrySendError::Full(delivery)) => delivery,
+			Err(TrySendError::Disconnected(_)) => panic!("Output thread stopped"),
+		};
+		if self.policy == FullPolicy::Block {
+			return self.sender.send(delivery).expect("Output thread stopped");
+		}
+
+		loop {
+			if let Ok(oldest) = self.receiver.try_recv() {
+				// Dropped on purpose, nothing is to read them again
+				oldest.ack.ack();
+				self.dropped += 1;
+				if self.dropped.is_multiple_of(DROP_REPORT) {
+					eprintln!(
+						"Output {} dropped {} records, its buffer is full",
+						self.output, self.dropped
+					);
+				}
+			}
+			match self.sender.try_send(delivery) {
+				Ok(()) => return,
+				Err(TrySendError::Full(d)) => delivery = d,
+				Err(TrySendError::Disconnected(_)) => panic!("Output thread stopped"),
+			}
+		}
+	}
+}
+
+/// Receiving end of the queue of an output, spilled records come after
+/// those in memory.
+pub struct BufferReceiver {
+	output: String,
+	receiver: Receiver<Delivery>,
+	spill: Option<Arc<Mutex<Spill>>>,
+}
+
+impl BufferReceiver {
+	fn unspill(&self) -> Option<Delivery> {
+		let mut spill = self.spill.as_ref()?.lock().expect("Spill file poisoned");
+		match spill.pop() {
+			Ok(delivery) => delivery,
+			Err(e) => {
+				eprintln!(
+					"Output {} lost {} spilled records: {}",
+					self.output, spill.pending, e
+				);
+				spill.clear().ok();
+				None
+			}
+		}
+	}
+
+	/// Next record, `Disconnected` once the sender is gone and everything
+	/// queued has been received.
+	pub fn recv_timeout(&self, timeout: Duration) -> Result<Delivery, RecvTimeoutError> {
+		if let Ok(delivery) = self.receiver.try_recv() {
+			return Ok(delivery);
+		}
+		if let Some(delivery) = self.unspill() {
+			return Ok(delivery);
+		}
+
+		match self.receiver.recv_timeout(timeout) {
+			Err(RecvTimeoutError::Disconnected) => {
+				self.unspill().ok_or(RecvTimeoutError::Disconnected)
+			}
+			received => received,
+		}
+	}
+}
+
+/// Bounded queue between the dispatcher and the thread of `output`. With
+/// `FullPolicy::Spill` the records that do not fit go to
+/// `<dir>/<output>.spill`, and keep going there until the output caught up
+/// so it still gets them in order.
+pub fn buffer(
+	output: &str,
+	capacity: usize,
+	policy: &FullPolicy,
+) -> io::Result<(BufferSender, BufferReceiver)> {
+	let (sender, receiver) = bounded(capacity);
+	let spill = match policy {
+		FullPolicy::Spill(dir) => Some(Arc::new(Mutex::new(Spill::open(dir, output)?))),
+		_ => None,
+	};
+
+	Ok((
+		BufferSender {
+			output: s!(output),
+			policy: policy.clone(),
+			sender,
+			receiver: receiver.clone(),
+			spill: spill.clone(),
+			dropped: 0,
+		},
+		BufferReceiver {
+			output: s!(output),
+			receiver,
+			spill,
+		},
+	))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::buffer;
+	use crate::config::FullPolicy;
+	use crate::pipeline::{Ack, Delivery, Message};
+	use crossbeam::channel::{unbounded, Receiver};
+	use std::{env, fs, time::Duration};
+
+	fn delivery(id: &str) -> (Delivery, Receiver<bool>) {
+		let (s, r) = unbounded();
+		let ack = Ack::new(move |ok| s.send(ok).unwrap_or(()));
+		(Delivery::new(Message::new(id, "key", id, "test"), ack), r)
+	}
+
+	#[test]
+	fn drops_the_oldest_records() {
+		let (mut sender, receiver) = buffer("test", 2, &FullPolicy::DropOldest).unwrap();
+		let (first, dropped) = delivery("1");
+		sender.send(first);
+		sender.send(delivery("2").0);
+		sender.send(delivery("3").0);
+
+		assert_eq!(Ok(true), dropped.try_recv());
+		let ids: Vec<String> = receiver.receiver.try_iter().map(|d| d.message.id).collect();
+		assert_eq!(vec!["2", "3"], ids);
+	}
+
+	#[test]
+	fn spills_in_order_and_keeps_the_file() {
+		let dir = env::temp_dir().join(format!("firehouse-spill-{}", std::process::id()));
+		let policy = FullPolicy::Spill(dir.to_string_lossy().into_owned());
+		let timeout = Duration::from_millis(10);
+
+		let (mut sender, receiver) = buffer("test", 1, &policy).unwrap();
+		let mut acks = Vec::new();
+		for id in &["1", "2", "3"] {
+			let (delivery, acked) = delivery(id);
+			sender.send(delivery);
+			acks.push(acked);
+		}
+		assert_eq!("1", receiver.recv_timeout(timeout).unwrap().message.id);
+		// Spilled records are settled once the output gets them
+		assert!(acks[1].try_recv().is_err());
+		let second = receiver.recv_timeout(timeout).unwrap();
+		assert_eq!("2", second.message.id);
+		second.ack.ack();
+		assert_eq!(Ok(true), acks[1].try_recv());
+		drop((sender, receiver));
+		// Those lost with the process fail
+		assert_eq!(Ok(false), acks[2].try_recv());
+
+		// What was not delivered is still there after a restart
+		let (mut sender, receiver) = buffer("test", 1, &policy).unwrap();
+		sender.send(delivery("4").0);
+		drop(sender);
+		// The file is only truncated once read entirely, 2 comes again
+		let ids: Vec<String> = (0..3)
+			.map(|_| receiver.recv_timeout(timeout).unwrap().message)
+			.map(|m| format!("{}:{}", m.id, String::from_utf8_lossy(m.data.as_ref())))
+			.collect();
+		assert_eq!(vec!["2:2", "3:3", "4:4"], ids);
+		assert!(receiver.recv_timeout(timeout).is_err());
+
+		fs::remove_dir_all(dir).unwrap();
+	}
+}
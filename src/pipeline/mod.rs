@@ -0,0 +1,260 @@
+use bytes::Bytes;
+use std::{
+	fmt, io,
+	sync::{
+		atomic::{AtomicBool, Ordering},
+		Arc, Mutex,
+	},
+	time::Duration,
+};
+
+use crate::checkpoint::CheckpointError;
+
+mod buffer;
+mod sinks;
+mod sources;
+
+pub use self::buffer::{buffer, BufferReceiver, BufferSender};
+pub use self::sinks::{
+	AmqpSink, FileSink, HttpSink, KinesisSink, PostgresSink, RedisSink, StdoutSink,
+};
+pub use self::sources::{AmqpSource, KinesisSource, RedisSource};
+
+#[derive(Debug)]
+pub enum PipelineError {
+	/// The source or sink cannot be used anymore.
+	Closed(String),
+	Io(io::Error),
+	Redis(redis::RedisError),
+	Amqp(amiquip::Error),
+	Http(reqwest::Error),
+	Postgres(road_postgres::Error),
+	Kinesis(String),
+	Checkpoint(CheckpointError),
+}
+
+impl fmt::Display for PipelineError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			PipelineError::Closed(e) => write!(f, "{}", e),
+			PipelineError::Io(e) => write!(f, "{}", e),
+			PipelineError::Redis(e) => write!(f, "redis: {}", e),
+			PipelineError::Amqp(e) => write!(f, "amqp: {}", e),
+			PipelineError::Http(e) => write!(f, "http: {}", e),
+			PipelineError::Postgres(e) => write!(f, "postgres: {}", e),
+			PipelineError::Kinesis(e) => write!(f, "kinesis: {}", e),
+			PipelineError::Checkpoint(e) => write!(f, "checkpoint: {}", e),
+		}
+	}
+}
+
+impl From<io::Error> for PipelineError {
+	fn from(e: io::Error) -> Self {
+		PipelineError::Io(e)
+	}
+}
+
+impl From<redis::RedisError> for PipelineError {
+	fn from(e: redis::RedisError) -> Self {
+		PipelineError::Redis(e)
+	}
+}
+
+impl From<amiquip::Error> for PipelineError {
+	fn from(e: amiquip::Error) -> Self {
+		PipelineError::Amqp(e)
+	}
+}
+
+impl From<reqwest::Error> for PipelineError {
+	fn from(e: reqwest::Error) -> Self {
+		PipelineError::Http(e)
+	}
+}
+
+impl From<road_postgres::Error> for PipelineError {
+	fn from(e: road_postgres::Error) -> Self {
+		PipelineError::Postgres(e)
+	}
+}
+
+impl From<CheckpointError> for PipelineError {
+	fn from(e: CheckpointError) -> Self {
+		PipelineError::Checkpoint(e)
+	}
+}
+
+/// A record travelling from a source to the sinks, whatever system it came
+/// from.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Message {
+	pub data: Bytes,
+	/// Partition key, routing key or channel the record was published with.
+	pub key: String,
+	/// Position of the record in its origin, like a sequence number.
+	pub id: String,
+	/// Stream, queue or channel the record was read from.
+	pub origin: String,
+}
+
+impl Message {
+	pub fn new<D: Into<Bytes>>(data: D, key: &str, id: &str, origin: &str) -> Self {
+		Message {
+			data: data.into(),
+			key: s!(key),
+			id: s!(id),
+			origin: s!(origin),
+		}
+	}
+
+	pub fn json(&self) -> serde_json::Result<serde_json::Value> {
+		serde_json::from_slice(self.data.as_ref())
+	}
+}
+
+type Settle = Box<dyn FnOnce(bool) + Send>;
+
+struct AckState {
+	failed: AtomicBool,
+	settle: Mutex<Option<Settle>>,
+}
+
+impl Drop for AckState {
+	fn drop(&mut self) {
+		let settle = self.settle.get_mut().map(Option::take).unwrap_or(None);
+		if let Some(settle) = settle {
+			settle(!self.failed.load(Ordering::SeqCst));
+		}
+	}
+}
+
+/// Tells the source whether a message was handled. Every clone of the
+/// handle has to be acked, once all of them are gone the source is told
+/// the message succeeded, or failed if any clone was dropped without
+/// `ack`.
+pub struct Ack {
+	state: Option<Arc<AckState>>,
+	acked: bool,
+}
+
+impl Ack {
+	/// `settle` runs once with whether the message succeeded.
+	pub fn new<F: FnOnce(bool) + Send + 'static>(settle: F) -> Self {
+		Ack {
+			state: Some(Arc::new(AckState {
+				failed: AtomicBool::new(false),
+				settle: Mutex::new(Some(Box::new(settle))),
+			})),
+			acked: false,
+		}
+	}
+
+	/// For sources that do not need to know.
+	pub fn none() -> Self {
+		Ack {
+			state: None,
+			acked: false,
+		}
+	}
+
+	pub fn ack(mut self) {
+		self.acked = true;
+	}
+
+	pub fn nack(self) {}
+}
+
+impl Clone for Ack {
+	fn clone(&self) -> Self {
+		Ack {
+			state: self.state.clone(),
+			acked: false,
+		}
+	}
+}
+
+impl Drop for Ack {
+	fn drop(&mut self) {
+		if let Some(state) = &self.state {
+			if !self.acked {
+				state.failed.store(true, Ordering::SeqCst);
+			}
+		}
+	}
+}
+
+/// A message and the handle to ack it.
+pub struct Delivery {
+	pub message: Message,
+	pub ack: Ack,
+}
+
+impl Delivery {
+	pub fn new(message: Message, ack: Ack) -> Self {
+		Delivery { message, ack }
+	}
+}
+
+/// Where messages come from: Kinesis, RabbitMQ, Redis...
+pub trait Source: Send {
+	/// Next message, `None` if nothing arrived within `timeout`.
+	fn recv(&mut self, timeout: Duration) -> Result<Option<Delivery>, PipelineError>;
+
+	/// Stops fetching messages and returns those fetched but not received,
+	/// `recv` still gives what it buffered otherwise.
+	fn stop(&mut self) -> Vec<Delivery> {
+		Vec::new()
+	}
+
+	/// Called once every message it gave was written or failed, before the
+	/// source is dropped.
+	fn close(&mut self) -> Result<(), PipelineError> {
+		Ok(())
+	}
+}
+
+/// Where messages end up: Kinesis, RabbitMQ, Redis, HTTP, Postgres...
+pub trait Sink: Send {
+	/// Writes the whole batch, an error means none of it may be considered
+	/// written.
+	fn write(&mut self, batch: &[Message]) -> Result<(), PipelineError>;
+
+	/// Writes whatever the sink buffered.
+	fn flush(&mut self) -> Result<(), PipelineError> {
+		Ok(())
+	}
+
+	/// Called once before the sink is dropped.
+	fn close(&mut self) -> Result<(), PipelineError> {
+		self.flush()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::Ack;
+	use crossbeam::channel::unbounded;
+
+	#[test]
+	fn settles_once_every_clone_is_gone() {
+		let (s, r) = unbounded();
+		let ack = Ack::new(move |ok| s.send(ok).unwrap());
+		let copy = ack.clone();
+
+		ack.ack();
+		assert!(r.try_recv().is_err());
+		copy.ack();
+		assert_eq!(Ok(true), r.try_recv());
+	}
+
+	#[test]
+	fn fails_if_any_clone_is_not_acked() {
+		let (s, r) = unbounded();
+		let ack = Ack::new(move |ok| s.send(ok).unwrap());
+		let copy = ack.clone();
+
+		ack.ack();
+		copy.nack();
+		assert_eq!(Ok(false), r.try_recv());
+	}
+}
@@ -0,0 +1,235 @@
+use amiquip::Publish;
+use redis::Commands;
+use road_postgres::PG;
+use rusoto_kinesis::PutRecordsRequestEntry;
+use std::{
+	fs::{File, OpenOptions},
+	io::{self, Write},
+	sync::Arc,
+};
+
+use super::{Message, PipelineError, Sink};
+use crate::connections::AmqpChannel;
+use crate::kinesis::{KinesisHandler, PartitionKeyStrategy};
+
+pub struct StdoutSink;
+
+impl Sink for StdoutSink {
+	fn write(&mut self, batch: &[Message]) -> Result<(), PipelineError> {
+		for message in batch {
+			println!("{}", String::from_utf8_lossy(message.data.as_ref()));
+		}
+
+		Ok(())
+	}
+}
+
+/// Appends one record per line.
+pub struct FileSink {
+	file: File,
+}
+
+impl FileSink {
+	pub fn open(path: &str) -> io::Result<Self> {
+		Ok(FileSink {
+			file: OpenOptions::new().create(true).append(true).open(path)?,
+		})
+	}
+}
+
+impl Sink for FileSink {
+	fn write(&mut self, batch: &[Message]) -> Result<(), PipelineError> {
+		let mut lines = Vec::new();
+		for message in batch {
+			lines.extend_from_slice(message.data.as_ref());
+			lines.push(b'\n');
+		}
+
+		Ok(self.file.write_all(&lines)?)
+	}
+
+	fn flush(&mut self) -> Result<(), PipelineError> {
+		Ok(self.file.flush()?)
+	}
+}
+
+pub struct AmqpSink {
+	channel: AmqpChannel,
+	exchange: String,
+	routing_key: Option<String>,
+}
+
+impl AmqpSink {
+	/// Publishes with `routing_key`, or the key of each message when `None`.
+	pub fn new(channel: AmqpChannel, exchange: &str, routing_key: Option<&str>) -> Self {
+		AmqpSink {
+			channel,
+			exchange: s!(exchange),
+			routing_key: routing_key.map(String::from),
+		}
+	}
+}
+
+impl Sink for AmqpSink {
+	fn write(&mut self, batch: &[Message]) -> Result<(), PipelineError> {
+		for message in batch {
+			let routing_key = self.routing_key.as_ref().unwrap_or(&message.key);
+			self.channel.basic_publish(
+				self.exchange.as_str(),
+				Publish::new(message.data.as_ref(), routing_key.as_str()),
+			)?;
+		}
+
+		Ok(())
+	}
+}
+
+pub struct RedisSink {
+	con: redis::Connection,
+	channel: String,
+}
+
+impl RedisSink {
+	pub fn new(con: redis::Connection, channel: &str) -> Self {
+		RedisSink {
+			con,
+			channel: s!(channel),
+		}
+	}
+}
+
+impl Sink for RedisSink {
+	fn write(&mut self, batch: &[Message]) -> Result<(), PipelineError> {
+		for message in batch {
+			let _: () = self.con.publish(&self.channel, message.data.as_ref())?;
+		}
+
+		Ok(())
+	}
+}
+
+/// POSTs each batch as newline separated records.
+pub struct HttpSink {
+	client: reqwest::Client,
+	url: String,
+}
+
+impl HttpSink {
+	pub fn new(url: &str) -> Self {
+		HttpSink {
+			client: reqwest::Client::new(),
+			url: s!(url),
+		}
+	}
+}
+
+impl Sink for HttpSink {
+	fn write(&mut self, batch: &[Message]) -> Result<(), PipelineError> {
+		let body: Vec<&[u8]> = batch.iter().map(|m| m.data.as_ref()).collect();
+
+		self.client
+			.post(&self.url)
+			.body(body.join(&b'\n'))
+			.send()?
+			.error_for_status()?;
+
+		Ok(())
+	}
+}
+
+/// Inserts `fields` of every JSON record in `table`, records that are not
+/// objects with all the fields are dropped.
+pub struct PostgresSink {
+	pg: Arc<PG>,
+	table: String,
+	fields: Vec<String>,
+}
+
+impl PostgresSink {
+	pub fn new(pg: Arc<PG>, table: &str, fields: Vec<String>) -> Self {
+		PostgresSink {
+			pg,
+			table: s!(table),
+			fields,
+		}
+	}
+}
+
+impl Sink for PostgresSink {
+	fn write(&mut self, batch: &[Message]) -> Result<(), PipelineError> {
+		let mut rows = Vec::new();
+		for message in batch {
+			match message.json() {
+				Ok(row) => rows.push(row),
+				Err(e) => println!("Dropping record {}: {}", message.id, e),
+			}
+		}
+
+		let skipped = self.pg.insert_batch(&self.table, &self.fields, rows)?;
+		if skipped > 0 {
+			println!(
+				"Dropped {} records without every field of {}",
+				skipped, self.table
+			);
+		}
+
+		Ok(())
+	}
+}
+
+/// Puts the records in a stream keeping their key, records without one get
+/// a random key.
+pub struct KinesisSink {
+	handler: KinesisHandler,
+	partition_key: Option<PartitionKeyStrategy>,
+}
+
+impl KinesisSink {
+	/// Without a `partition_key` strategy records keep the key they were
+	/// read with, or get a random one.
+	pub fn new(handler: KinesisHandler, partition_key: Option<PartitionKeyStrategy>) -> Self {
+		KinesisSink {
+			handler,
+			partition_key,
+		}
+	}
+}
+
+impl Sink for KinesisSink {
+	fn write(&mut self, batch: &[Message]) -> Result<(), PipelineError> {
+		let records = batch
+			.iter()
+			.map(|m| {
+				let (partition_key, explicit_hash_key) = match &self.partition_key {
+					Some(strategy) => {
+						let record = serde_json::from_slice(m.data.as_ref()).ok();
+						strategy.keys(m.data.as_ref(), record.as_ref())
+					}
+					None if m.key.is_empty() => PartitionKeyStrategy::Random.keys(&[], None),
+					None => (m.key.clone(), None),
+				};
+				PutRecordsRequestEntry {
+					data: m.data.clone(),
+					explicit_hash_key,
+					partition_key,
+				}
+			})
+			.collect();
+
+		let failed = self
+			.handler
+			.put_entries(records)
+			.map_err(|e| PipelineError::Kinesis(e.to_string()))?;
+
+		match failed.first() {
+			Some(first) => Err(PipelineError::Kinesis(format!(
+				"{} of {} records still failed after retrying, like {}: {}",
+				failed.len(),
+				batch.len(),
+				first.error_code,
+				first.error_message
+			))),
+			None => Ok(()),
+		}
+	}
+}
@@ -0,0 +1,305 @@
+use amiquip::{ConsumerMessage, ConsumerOptions, QueueDeclareOptions};
+use crossbeam::channel::{never, select, unbounded, Receiver, RecvTimeoutError};
+use std::{
+	collections::HashMap,
+	sync::{
+		atomic::{AtomicBool, Ordering},
+		Arc,
+	},
+	thread,
+	time::Duration,
+};
+
+use super::{Ack, Delivery, Message, PipelineError, Source};
+use crate::checkpoint::{CheckpointStore, Checkpointer};
+use crate::connections::{AmqpChannel, ConnectionError};
+use crate::kinesis::{KinesisError, KinesisHandler, RecordStream, StartPosition};
+use crate::kpl::UserRecord;
+use crate::lease::Leases;
+
+fn recv<T>(
+	receiver: &Receiver<T>,
+	timeout: Duration,
+	origin: &str,
+) -> Result<Option<T>, PipelineError> {
+	match receiver.recv_timeout(timeout) {
+		Ok(item) => Ok(Some(item)),
+		Err(RecvTimeoutError::Timeout) => Ok(None),
+		Err(RecvTimeoutError::Disconnected) => {
+			Err(PipelineError::Closed(format!("{} stopped", origin)))
+		}
+	}
+}
+
+/// Reads every shard of a stream from a start position, or from where the
+/// checkpoints of the input left it. With leases, workers reading the same
+/// input share its shards. Once stopped, closing it saves the checkpoints
+/// of what the outputs acked.
+pub struct KinesisSource {
+	records: RecordStream<(String, UserRecord)>,
+	stream: String,
+	checkpointer: Option<Checkpointer>,
+}
+
+impl KinesisSource {
+	pub fn new(handler: KinesisHandler, start: StartPosition) -> Result<Self, KinesisError> {
+		Ok(KinesisSource {
+			stream: handler.stream().to_string(),
+			records: handler.resume_records_stream(|_| None, start, None, never())?,
+			checkpointer: None,
+		})
+	}
+
+	/// Resumes each shard after its checkpoint in `store`, those without one
+	/// start from `start`. Checkpoints move
+	/// forward as the outputs ack the records. With `leases` only the shards
+	/// leased to this worker are read, and the checkpoints are loaded again
+	/// when one is taken over so it resumes where the previous worker left it.
+	pub fn resume(
+		handler: KinesisHandler,
+		input: &str,
+		start: StartPosition,
+		mut store: Box<dyn CheckpointStore>,
+		leases: Option<Leases>,
+	) -> Result<Self, ConnectionError> {
+		let checkpoints = store.load()?;
+		let checkpointer = Checkpointer::start(input, store);
+
+		let rewinds = checkpointer.rewinds();
+		let shared = leases.is_some();
+		let (loader, input) = (checkpointer.clone(), s!(input));
+		let after = move |shard_id: &str| {
+			if !shared {
+				return checkpoints.get(shard_id).cloned();
+			}
+			match loader.load() {
+				Ok(loaded) => loaded.get(shard_id).cloned(),
+				Err(e) => {
+					eprintln!("Input {} cannot load its checkpoints: {}", input, e);
+					checkpoints.get(shard_id).cloned()
+				}
+			}
+		};
+
+		Ok(KinesisSource {
+			stream: handler.stream().to_string(),
+			records: handler.resume_records_stream(after, start, leases, rewinds)?,
+			checkpointer: Some(checkpointer),
+		})
+	}
+
+	fn deliver(&self, (shard_id, r): (String, UserRecord)) -> Delivery {
+		let ack = match &self.checkpointer {
+			Some(checkpointer) => {
+				let first = r.sub_sequence_number.unwrap_or(0) == 0;
+				checkpointer.track(&shard_id, &r.sequence_number, first)
+			}
+			None => Ack::none(),
+		};
+		let message = Message::new(r.data.clone(), &r.partition_key, &r.id(), &self.stream);
+		Delivery::new(message, ack)
+	}
+}
+
+impl Source for KinesisSource {
+	fn recv(&mut self, timeout: Duration) -> Result<Option<Delivery>, PipelineError> {
+		Ok(recv(self.records.records(), timeout, &self.stream)?.map(|r| self.deliver(r)))
+	}
+
+	fn stop(&mut self) -> Vec<Delivery> {
+		let records = self.records.shutdown();
+		records.into_iter().map(|r| self.deliver(r)).collect()
+	}
+
+	/// Saves the final checkpoints, and only then gives the leases up.
+	fn close(&mut self) -> Result<(), PipelineError> {
+		let flushed = match &self.checkpointer {
+			Some(checkpointer) => checkpointer.flush(),
+			None => Ok(()),
+		};
+		self.records.release();
+
+		Ok(flushed?)
+	}
+}
+
+/// Consumes a RabbitMQ queue. Messages are acked on the queue once every
+/// output wrote them. One that failed is requeued, unless it was delivered
+/// before: it is then rejected for good and goes to the dead letter exchange
+/// of the queue, if any, so a message that always fails is not delivered over
+/// and over. The ones still unacked when the source is dropped are
+/// redelivered by RabbitMQ.
+pub struct AmqpSource {
+	deliveries: Receiver<Result<Delivery, PipelineError>>,
+	queue: String,
+	stop: Arc<AtomicBool>,
+}
+
+impl AmqpSource {
+	pub fn new(channel: AmqpChannel, queue: &str) -> Result<Self, amiquip::Error> {
+		// The consumer borrows the channel so both live in the thread
+		let (ready, started) = unbounded();
+		let (s, deliveries) = unbounded();
+		let stop = Arc::new(AtomicBool::new(false));
+		let flag = stop.clone();
+		let name = s!(queue);
+
+		thread::spawn(move || {
+			let consumer = match channel
+				.queue_declare(name.as_str(), QueueDeclareOptions::default())
+				.and_then(|q| q.consume(ConsumerOptions::default()))
+			{
+				Ok(consumer) => {
+					let _ = ready.send(Ok(()));
+					consumer
+				}
+				Err(e) => {
+					let _ = ready.send(Err(e));
+					return;
+				}
+			};
+
+			let (settled, acks) = unbounded();
+			let mut pending = HashMap::new();
+
+			while !flag.load(Ordering::SeqCst) {
+				select! {
+					recv(consumer.receiver()) -> message => match message {
+						Ok(ConsumerMessage::Delivery(delivery)) => {
+							let tag = delivery.delivery_tag();
+							let message = Message::new(
+								delivery.body.clone(),
+								&delivery.routing_key,
+								&tag.to_string(),
+								&name,
+							);
+							let settled = settled.clone();
+							let ack = Ack::new(move |ok| {
+								let _ = settled.send((tag, ok));
+							});
+
+							pending.insert(tag, delivery);
+							if s.send(Ok(Delivery::new(message, ack))).is_err() {
+								return;
+							}
+						}
+						other => {
+							let _ = s.send(Err(PipelineError::Closed(format!(
+								"queue {} closed: {:?}",
+								name, other
+							))));
+							return;
+						}
+					},
+					recv(acks) -> settled => {
+						let (tag, ok) = settled.expect("Ack channel closed");
+						if let Some(delivery) = pending.remove(&tag) {
+							let result = if ok {
+								consumer.ack(delivery)
+							} else {
+								// Requeued once, then dead lettered if the queue says so
+								let requeue = !delivery.redelivered;
+								consumer.nack(delivery, requeue)
+							};
+							if let Err(e) = result {
+								let _ = s.send(Err(e.into()));
+								return;
+							}
+						}
+					},
+					default(Duration::from_millis(500)) => {}
+				}
+			}
+		});
+
+		started.recv().expect("Consumer thread stopped")?;
+
+		Ok(AmqpSource {
+			deliveries,
+			queue: s!(queue),
+			stop,
+		})
+	}
+}
+
+impl Source for AmqpSource {
+	fn recv(&mut self, timeout: Duration) -> Result<Option<Delivery>, PipelineError> {
+		recv(&self.deliveries, timeout, &self.queue)?.transpose()
+	}
+}
+
+impl Drop for AmqpSource {
+	fn drop(&mut self) {
+		self.stop.store(true, Ordering::SeqCst);
+	}
+}
+
+/// Subscribes to a Redis pub/sub channel, what is published while nobody
+/// listens is lost.
+pub struct RedisSource {
+	messages: Receiver<Result<Message, PipelineError>>,
+	channel: String,
+	stop: Arc<AtomicBool>,
+}
+
+impl RedisSource {
+	pub fn new(mut con: redis::Connection, channel: &str) -> Result<Self, redis::RedisError> {
+		// The subscription borrows the connection so both live in the thread
+		let (ready, started) = unbounded();
+		let (s, messages) = unbounded();
+		let stop = Arc::new(AtomicBool::new(false));
+		let flag = stop.clone();
+		let name = s!(channel);
+
+		thread::spawn(move || {
+			let mut pubsub = con.as_pubsub();
+			let subscribed = pubsub
+				.subscribe(name.as_str())
+				.and_then(|_| pubsub.set_read_timeout(Some(Duration::from_millis(500))));
+			let failed = subscribed.is_err();
+			let _ = ready.send(subscribed);
+			if failed {
+				return;
+			}
+
+			while !flag.load(Ordering::SeqCst) {
+				let message = match pubsub.get_message() {
+					Ok(m) => Ok(Message::new(
+						m.get_payload_bytes().to_vec(),
+						&name,
+						"",
+						&name,
+					)),
+					Err(ref e) if e.is_timeout() => continue,
+					Err(e) => Err(e.into()),
+				};
+				let failed = message.is_err();
+				if s.send(message).is_err() || failed {
+					return;
+				}
+			}
+		});
+
+		started.recv().expect("Subscriber thread stopped")?;
+
+		Ok(RedisSource {
+			messages,
+			channel: s!(channel),
+			stop,
+		})
+	}
+}
+
+impl Source for RedisSource {
+	fn recv(&mut self, timeout: Duration) -> Result<Option<Delivery>, PipelineError> {
+		Ok(recv(&self.messages, timeout, &self.channel)?
+			.transpose()?
+			.map(|m| Delivery::new(m, Ack::none())))
+	}
+}
+
+impl Drop for RedisSource {
+	fn drop(&mut self) {
+		self.stop.store(true, Ordering::SeqCst);
+	}
+}
@@ -0,0 +1,508 @@
+use bytes::Bytes;
+use crossbeam::channel::{unbounded, RecvTimeoutError, Sender};
+use rusoto_kinesis::PutRecordsRequestEntry;
+use std::{
+	collections::HashMap,
+	mem,
+	thread::{self, JoinHandle},
+	time::{Duration, Instant},
+};
+
+use crate::kinesis::{
+	DeadLetter, FailedRecord, KinesisError, KinesisHandler, PartitionKeyStrategy,
+};
+use crate::kpl::Aggregate;
+
+/// Records a `PutRecords` call takes at most.
+pub const MAX_BATCH_RECORDS: usize = 500;
+/// Size of a `PutRecords` call at most, data and partition keys included.
+pub const MAX_BATCH_BYTES: usize = 5 * 1024 * 1024;
+/// Size of a record at most, data and partition key included.
+pub const MAX_RECORD_BYTES: usize = 1024 * 1024;
+
+/// Error code of the records refused before being sent, as too large.
+pub const RECORD_TOO_LARGE: &str = "RecordTooLarge";
+/// Error code of the records that cannot be serialized, their entry is empty.
+pub const NOT_SERIALIZABLE: &str = "NotSerializable";
+/// Error code of the records of a `PutRecords` call that failed as a whole.
+pub const REQUEST_FAILED: &str = "RequestFailed";
+
+pub(crate) fn size(entry: &PutRecordsRequestEntry) -> usize {
+	entry.data.len() + entry.partition_key.len()
+}
+
+/// What became of the records sent to a producer since the last report.
+/// With aggregation the records put and failed are the aggregated ones.
+#[derive(Debug, Default)]
+pub struct DeliveryReport {
+	/// Records Kinesis accepted.
+	pub put: usize,
+	pub failed: Vec<FailedRecord>,
+}
+
+impl DeliveryReport {
+	pub(crate) fn give_up(&mut self, records: Vec<FailedRecord>, dead_letter: &Option<DeadLetter>) {
+		if let Some(dead_letter) = dead_letter {
+			for record in records.iter() {
+				dead_letter(record.clone());
+			}
+		}
+		self.failed.extend(records);
+	}
+}
+
+enum Command<T> {
+	Put(T),
+	Flush(Sender<DeliveryReport>),
+}
+
+/// Records waiting for the next `PutRecords` call.
+pub(crate) struct Batch {
+	entries: Vec<PutRecordsRequestEntry>,
+	bytes: usize,
+	oldest: Option<Instant>,
+}
+
+impl Batch {
+	pub(crate) fn new() -> Self {
+		Batch {
+			entries: Vec::new(),
+			bytes: 0,
+			oldest: None,
+		}
+	}
+
+	pub(crate) fn fits(&self, entry: &PutRecordsRequestEntry) -> bool {
+		self.entries.len() < MAX_BATCH_RECORDS && self.bytes + size(entry) <= MAX_BATCH_BYTES
+	}
+
+	pub(crate) fn push(&mut self, entry: PutRecordsRequestEntry) {
+		self.bytes += size(&entry);
+		self.oldest.get_or_insert_with(Instant::now);
+		self.entries.push(entry);
+	}
+
+	pub(crate) fn take(&mut self) -> Vec<PutRecordsRequestEntry> {
+		mem::replace(self, Batch::new()).entries
+	}
+}
+
+/// Whether `aggregate` put with `entry` added stays within a record, the
+/// partition key it is put with included.
+fn fits(aggregate: &Aggregate, entry: &PutRecordsRequestEntry) -> bool {
+	let hash_key = entry.explicit_hash_key.as_deref();
+	let partition_key = aggregate
+		.keys()
+		.map_or(entry.partition_key.len(), |(key, _)| key.len());
+
+	aggregate.size_with(&entry.partition_key, hash_key, &entry.data) + partition_key
+		<= MAX_RECORD_BYTES
+}
+
+/// Partition and explicit hash keys records are aggregated by, `None` for
+/// random keys.
+type AggregateKey = Option<(String, Option<String>)>;
+
+/// Records a producer thread holds until they are put.
+struct Worker<P> {
+	put: P,
+	dead_letter: Option<DeadLetter>,
+	strategy: PartitionKeyStrategy,
+	batch: Batch,
+	/// Records being aggregated, by partition and explicit hash keys so the
+	/// records of a key keep their shard. Random keys are all aggregated
+	/// together.
+	aggregates: Option<HashMap<AggregateKey, Aggregate>>,
+	/// When the oldest record being aggregated came.
+	aggregated: Option<Instant>,
+	report: DeliveryReport,
+}
+
+impl<P> Worker<P>
+where
+	P: FnMut(Vec<PutRecordsRequestEntry>) -> Result<Vec<FailedRecord>, KinesisError>,
+{
+	/// When the oldest record waiting came, if any.
+	fn oldest(&self) -> Option<Instant> {
+		match (self.batch.oldest, self.aggregated) {
+			(Some(a), Some(b)) => Some(a.min(b)),
+			(a, b) => a.or(b),
+		}
+	}
+
+	fn give_up(&mut self, entry: PutRecordsRequestEntry, error_code: &str, error_message: String) {
+		let record = FailedRecord {
+			entry,
+			error_code: s!(error_code),
+			error_message,
+		};
+		self.report.give_up(vec![record], &self.dead_letter);
+	}
+
+	fn add<T: serde::Serialize>(&mut self, item: T) {
+		let entry = match KinesisHandler::create_record_from(item, &self.strategy) {
+			Ok(entry) => entry,
+			Err(e) => return self.give_up(Default::default(), NOT_SERIALIZABLE, e.to_string()),
+		};
+		if size(&entry) > MAX_RECORD_BYTES {
+			let message = format!(
+				"{} bytes, at most {} are allowed",
+				size(&entry),
+				MAX_RECORD_BYTES
+			);
+			return self.give_up(entry, RECORD_TOO_LARGE, message);
+		}
+
+		let aggregates = match &mut self.aggregates {
+			Some(aggregates) => aggregates,
+			None => return self.batch(entry),
+		};
+		let group = match self.strategy {
+			PartitionKeyStrategy::Random => None,
+			_ => Some((entry.partition_key.clone(), entry.explicit_hash_key.clone())),
+		};
+		let aggregate = aggregates.entry(group).or_insert_with(Aggregate::new);
+		let full = if fits(aggregate, &entry) {
+			None
+		} else {
+			Some(mem::replace(aggregate, Aggregate::new()))
+		};
+		// A record too large for an aggregate of its own is put as it is
+		let plain = if fits(aggregate, &entry) {
+			let hash_key = entry.explicit_hash_key.as_deref();
+			aggregate.push(&entry.partition_key, hash_key, entry.data);
+			self.aggregated.get_or_insert_with(Instant::now);
+			None
+		} else {
+			Some(entry)
+		};
+
+		if let Some(full) = full {
+			self.batch_aggregate(full);
+		}
+		if let Some(entry) = plain {
+			self.batch(entry);
+		}
+	}
+
+	fn batch_aggregate(&mut self, aggregate: Aggregate) {
+		let (partition_key, explicit_hash_key) = match aggregate.keys() {
+			Some(keys) => keys,
+			None => return,
+		};
+		let entry = PutRecordsRequestEntry {
+			data: Bytes::from(aggregate.encode()),
+			partition_key,
+			explicit_hash_key,
+		};
+		self.batch(entry);
+	}
+
+	fn batch(&mut self, entry: PutRecordsRequestEntry) {
+		if !self.batch.fits(&entry) {
+			self.send();
+		}
+		self.batch.push(entry);
+		if self.batch.entries.len() == MAX_BATCH_RECORDS {
+			self.send();
+		}
+	}
+
+	/// Puts everything waiting.
+	fn flush(&mut self) {
+		let aggregates = self.aggregates.as_mut().map(mem::take);
+		for (_, aggregate) in aggregates.into_iter().flatten() {
+			self.batch_aggregate(aggregate);
+		}
+		self.aggregated = None;
+		self.send();
+	}
+
+	fn send(&mut self) {
+		let entries = self.batch.take();
+		if entries.is_empty() {
+			return;
+		}
+
+		let count = entries.len();
+		let failed = match (self.put)(entries.clone()) {
+			Ok(failed) => failed,
+			Err(e) => entries
+				.into_iter()
+				.map(|entry| FailedRecord {
+					entry,
+					error_code: s!(REQUEST_FAILED),
+					error_message: e.to_string(),
+				})
+				.collect(),
+		};
+		self.report.put += count - failed.len();
+		self.report.give_up(failed, &self.dead_letter);
+	}
+}
+
+/// Puts the records sent to it in batches as large as Kinesis allows, a
+/// batch leaves once full or once its oldest record waited `linger`. With
+/// aggregation records are first packed into KPL aggregated records of up
+/// to 1 MiB. Dropping the producer puts what is left but nobody learns how
+/// it went, `close` tells.
+pub struct Producer<T> {
+	commands: Sender<Command<T>>,
+	thread: JoinHandle<DeliveryReport>,
+}
+
+impl<T: serde::Serialize + Send + 'static> Producer<T> {
+	pub(crate) fn start(
+		handler: KinesisHandler,
+		linger: Duration,
+		strategy: PartitionKeyStrategy,
+	) -> Self {
+		let worker = Worker {
+			dead_letter: handler.dead_letter(),
+			strategy,
+			batch: Batch::new(),
+			aggregates: if handler.aggregation() {
+				Some(HashMap::new())
+			} else {
+				None
+			},
+			aggregated: None,
+			report: DeliveryReport::default(),
+			put: move |entries| handler.put_entries(entries),
+		};
+
+		Producer::with_worker(linger, worker)
+	}
+
+	fn with_worker<P>(linger: Duration, mut worker: Worker<P>) -> Self
+	where
+		P: FnMut(Vec<PutRecordsRequestEntry>) -> Result<Vec<FailedRecord>, KinesisError>
+			+ Send
+			+ 'static,
+	{
+		let (commands, r) = unbounded();
+
+		let thread = thread::spawn(move || loop {
+			let command = match worker.oldest() {
+				Some(oldest) => r.recv_timeout(linger.saturating_sub(oldest.elapsed())),
+				None => r.recv().map_err(|_| RecvTimeoutError::Disconnected),
+			};
+
+			match command {
+				Ok(Command::Put(item)) => worker.add(item),
+				Ok(Command::Flush(reply)) => {
+					worker.flush();
+					let _ = reply.send(mem::take(&mut worker.report));
+				}
+				Err(RecvTimeoutError::Timeout) => worker.flush(),
+				Err(RecvTimeoutError::Disconnected) => {
+					worker.flush();
+					return worker.report;
+				}
+			}
+		});
+
+		Producer { commands, thread }
+	}
+
+	pub fn send(&self, item: T) {
+		self.commands
+			.send(Command::Put(item))
+			.expect("Producer thread stopped");
+	}
+
+	/// Puts the records waiting for their batch to fill and reports on every
+	/// record sent since the last report.
+	pub fn flush(&self) -> DeliveryReport {
+		let (reply, report) = unbounded();
+		self.commands
+			.send(Command::Flush(reply))
+			.expect("Producer thread stopped");
+
+		report.recv().expect("Producer thread stopped")
+	}
+
+	/// Puts what is left and reports on it, once the producer thread ended.
+	pub fn close(self) -> DeliveryReport {
+		drop(self.commands);
+
+		self.thread.join().expect("Producer thread panicked")
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{
+		size, Batch, DeliveryReport, Producer, Worker, MAX_BATCH_RECORDS, MAX_RECORD_BYTES,
+		NOT_SERIALIZABLE, RECORD_TOO_LARGE,
+	};
+	use crate::kinesis::{DeadLetter, FailedRecord, KinesisError, PartitionKeyStrategy};
+	use crate::kpl::deaggregate;
+	use crossbeam::channel::unbounded;
+	use rusoto_kinesis::PutRecordsRequestEntry;
+	use std::{collections::HashMap, sync::Arc, time::Duration};
+
+	type Put = Box<
+		dyn FnMut(Vec<PutRecordsRequestEntry>) -> Result<Vec<FailedRecord>, KinesisError> + Send,
+	>;
+
+	fn worker(put: Put, strategy: PartitionKeyStrategy, aggregate: bool) -> Worker<Put> {
+		Worker {
+			put,
+			dead_letter: None,
+			strategy,
+			batch: Batch::new(),
+			aggregates: if aggregate {
+				Some(HashMap::new())
+			} else {
+				None
+			},
+			aggregated: None,
+			report: DeliveryReport::default(),
+		}
+	}
+
+	fn refusing_the_first(entries: Vec<PutRecordsRequestEntry>) -> Vec<FailedRecord> {
+		entries
+			.into_iter()
+			.take(1)
+			.map(|entry| FailedRecord {
+				entry,
+				error_code: s!("ProvisionedThroughputExceededException"),
+				error_message: s!(""),
+			})
+			.collect()
+	}
+
+	#[test]
+	fn batches_by_count_and_time() {
+		let (s, batches) = unbounded();
+		let put: Put = Box::new(move |entries| {
+			s.send(entries.len()).unwrap();
+			Ok(refusing_the_first(entries))
+		});
+		let producer = Producer::with_worker(
+			Duration::from_millis(50),
+			worker(put, PartitionKeyStrategy::Random, false),
+		);
+
+		for i in 0..MAX_BATCH_RECORDS + 2 {
+			producer.send(i);
+		}
+		assert_eq!(Ok(MAX_BATCH_RECORDS), batches.recv());
+		// The last two leave once they waited long enough
+		assert_eq!(Ok(2), batches.recv_timeout(Duration::from_secs(1)));
+
+		producer.send(0);
+		let report = producer.flush();
+		assert_eq!(Ok(1), batches.try_recv());
+		assert_eq!(MAX_BATCH_RECORDS, report.put);
+		assert_eq!(3, report.failed.len());
+		assert_eq!(0, producer.close().put);
+	}
+
+	#[test]
+	fn batches_by_size() {
+		let (s, batches) = unbounded();
+		let (dead, letters) = unbounded();
+		let put: Put = Box::new(move |entries| {
+			s.send(entries.len()).unwrap();
+			Ok(vec![])
+		});
+		let mut worker = worker(put, PartitionKeyStrategy::Random, false);
+		let dead_letter: DeadLetter = Arc::new(move |record| dead.send(record).unwrap());
+		worker.dead_letter = Some(dead_letter);
+		let producer = Producer::with_worker(Duration::from_secs(60), worker);
+		let record = "x".repeat(400 * 1024);
+
+		// 13 records of 400 KiB go over the 5 MiB of a request
+		for _ in 0..13 {
+			producer.send(record.clone());
+		}
+		producer.send(record.repeat(3));
+		let report = producer.close();
+
+		assert_eq!(vec![12, 1], batches.try_iter().collect::<Vec<usize>>());
+		assert_eq!(13, report.put);
+		assert_eq!(RECORD_TOO_LARGE, report.failed[0].error_code);
+		assert_eq!(1, letters.try_iter().count());
+	}
+
+	#[test]
+	fn gives_up_on_records_that_cannot_be_serialized() {
+		let put: Put = Box::new(|_| Ok(vec![]));
+		let mut worker = worker(put, PartitionKeyStrategy::Random, false);
+		let mut record = HashMap::new();
+		// JSON keys must be strings
+		record.insert((1, 2), 3);
+
+		worker.add(record);
+		assert_eq!(NOT_SERIALIZABLE, worker.report.failed[0].error_code);
+		assert!(worker.batch.take().is_empty());
+	}
+
+	#[test]
+	fn aggregates_the_records_of_a_key() {
+		let (s, puts) = unbounded();
+		let put: Put = Box::new(move |entries| {
+			s.send(entries).unwrap();
+			Ok(vec![])
+		});
+		let strategy = PartitionKeyStrategy::Field(s!("tenant"));
+		let producer = Producer::with_worker(Duration::from_secs(60), worker(put, strategy, true));
+
+		for tenant in &["acme", "other", "acme"] {
+			producer.send(serde_json::json!({ "tenant": tenant }));
+		}
+		// Two aggregates of 600 KiB do not fit in a record
+		let large = "x".repeat(600 * 1024);
+		for _ in 0..2 {
+			producer.send(serde_json::json!({ "tenant": "big", "data": large }));
+		}
+		assert_eq!(4, producer.close().put);
+
+		let mut entries = puts
+			.try_iter()
+			.flatten()
+			.collect::<Vec<PutRecordsRequestEntry>>();
+		entries.sort_by(|a, b| a.partition_key.cmp(&b.partition_key));
+		let records: Vec<(String, usize)> = entries
+			.iter()
+			.map(|e| {
+				(
+					e.partition_key.clone(),
+					deaggregate(e.data.as_ref()).unwrap().len(),
+				)
+			})
+			.collect();
+		assert_eq!(
+			vec![
+				(s!("acme"), 2),
+				(s!("big"), 1),
+				(s!("big"), 1),
+				(s!("other"), 1)
+			],
+			records
+		);
+	}
+
+	#[test]
+	fn puts_records_too_large_to_aggregate_as_they_are() {
+		let (s, puts) = unbounded();
+		let put: Put = Box::new(move |entries| {
+			s.send(entries).unwrap();
+			Ok(vec![])
+		});
+		let mut worker = worker(put, PartitionKeyStrategy::Random, true);
+		let record = "x".repeat(MAX_RECORD_BYTES - 64);
+
+		worker.add(&record);
+		worker.add(&record[..1024]);
+		worker.flush();
+		let entries = puts.try_iter().flatten().collect::<Vec<_>>();
+		assert_eq!(2, entries.len());
+		assert!(deaggregate(entries[0].data.as_ref()).is_none());
+		assert!(entries.iter().all(|e| size(e) <= MAX_RECORD_BYTES));
+		assert_eq!(1, deaggregate(entries[1].data.as_ref()).unwrap().len());
+	}
+}
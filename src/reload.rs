@@ -0,0 +1,54 @@
+use crossbeam::channel::Sender;
+use signal_hook::SIGHUP;
+use std::{
+	fs,
+	sync::{
+		atomic::{AtomicBool, Ordering},
+		Arc,
+	},
+	thread,
+	time::{Duration, SystemTime},
+};
+
+use crate::config::FirehoseConfig;
+
+fn modified(path: &str) -> Option<SystemTime> {
+	fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// Sends the configuration at `path` again every time the file changes or
+/// the process gets a SIGHUP. Invalid configurations are reported and never
+/// sent, so the running pipeline is kept.
+pub fn watch(path: String, reloads: Sender<FirehoseConfig>) {
+	let hup = Arc::new(AtomicBool::new(false));
+	signal_hook::flag::register(SIGHUP, hup.clone()).expect("Cannot listen to SIGHUP");
+
+	thread::spawn(move || {
+		let mut last_modified = modified(&path);
+
+		loop {
+			thread::sleep(Duration::from_secs(1));
+
+			let current = modified(&path);
+			if !hup.swap(false, Ordering::SeqCst) && current == last_modified {
+				continue;
+			}
+			last_modified = current;
+
+			println!("Reloading {}", path);
+			match FirehoseConfig::load(&path) {
+				Ok(config) => {
+					if reloads.send(config).is_err() {
+						return;
+					}
+				}
+				Err(errors) => {
+					eprintln!("Rejecting the new configuration, the running one is kept");
+					for e in errors {
+						eprintln!("{}: {}", path, e);
+					}
+				}
+			}
+		}
+	});
+}
@@ -0,0 +1,195 @@
+use amiquip::{Exchange, Publish};
+use rand::prelude::*;
+use redis::Commands;
+use serde_json::{from_str, Value};
+use std::time::Duration;
+
+use crate::config::Route;
+use crate::connections::AmqpChannel;
+use crate::pipeline::{self, Message, PipelineError};
+
+pub fn get_tenant(l: &Value) -> String {
+	l["metadata"]["tenant"].to_string()
+}
+
+pub fn get_type(l: &Value) -> String {
+	l["metadata"]["type"].to_string()
+}
+
+pub fn get_sink(conf: &Value) -> String {
+	conf["sink"].to_string()
+}
+
+pub fn get_topic(conf: &Value, l: &Value) -> String {
+	let tenant = get_tenant(l);
+	let log_type = get_type(l);
+	let destination = get_sink(conf);
+
+	format!("all.{}.{}.{}", destination, log_type, tenant)
+}
+
+/// Replaces `{tenant}` and `{type}` in the topic of a route.
+pub fn fill_topic(topic: &str, l: &Value) -> String {
+	let field = |name: &str| match &l["metadata"][name] {
+		Value::String(s) => s.clone(),
+		other => other.to_string(),
+	};
+
+	topic
+		.replace("{tenant}", &field("tenant"))
+		.replace("{type}", &field("type"))
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct Sink {
+	url: String,
+	batch: usize,
+	interval: Duration,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct Config {
+	tenant: String,
+	sinks: Vec<Sink>,
+}
+
+impl Config {
+	pub fn new(tenant: String) -> Self {
+		let n_sinks: usize = rand::thread_rng().gen_range(1, 3);
+
+		let sinks: Vec<Sink> = (0..n_sinks)
+			.map(|_| Sink {
+				url: format!("http://192.169.1.129/check/{}", tenant),
+				batch: rand::thread_rng().gen_range(1, 1000),
+				interval: Duration::new(rand::thread_rng().gen_range(1, 300), 0),
+			})
+			.collect::<Vec<Sink>>();
+
+		Config { tenant, sinks }
+	}
+}
+
+/// Where a log goes according to the routes and the tenant configurations.
+#[derive(Debug, PartialEq)]
+pub enum Resolution {
+	/// Route `index` matched, the log goes to the topic or is dropped if
+	/// there is none.
+	Route(usize, Option<String>),
+	/// The tenant configuration gives the topic.
+	Tenant { config: Value, topic: String },
+	/// The stored configuration cannot be parsed, the log is dropped.
+	InvalidConfig(String),
+	/// There is no configuration for the tenant yet.
+	Unknown,
+}
+
+/// Resolves where a log goes without publishing nor writing anything,
+/// `configs` looks up the stored configuration of a tenant.
+pub fn resolve<F>(routes: &[Route], configs: F, l: &Value) -> Result<Resolution, PipelineError>
+where
+	F: FnOnce(&str) -> redis::RedisResult<Option<String>>,
+{
+	if let Some((i, route)) = routes.iter().enumerate().find(|(_, r)| r.when.matches(l)) {
+		let topic = route.topic.as_ref().map(|t| fill_topic(t, l));
+		return Ok(Resolution::Route(i, topic));
+	}
+
+	let config = configs(&get_tenant(l))?;
+	Ok(match config.map(|c| from_str::<Value>(&c)) {
+		Some(Ok(config)) => Resolution::Tenant {
+			topic: get_topic(&config, l),
+			config,
+		},
+		Some(Err(e)) => Resolution::InvalidConfig(e.to_string()),
+		None => Resolution::Unknown,
+	})
+}
+
+/// Publishes logs to RabbitMQ using the per tenant configuration stored in Redis.
+pub struct Router {
+	con: redis::Connection,
+	channel: AmqpChannel,
+	routes: Vec<Route>,
+}
+
+impl Router {
+	pub fn new(con: redis::Connection, channel: AmqpChannel, routes: Vec<Route>) -> Self {
+		Router {
+			con,
+			channel,
+			routes,
+		}
+	}
+
+	fn publish(&self, l: &Value, topic: String) -> Result<(), PipelineError> {
+		let l_string = l.to_string();
+		let message = Publish::new(l_string.as_bytes(), topic.clone());
+		println!("Publishing topic: {}", topic);
+		Exchange::direct(&self.channel).publish(message)?;
+
+		Ok(())
+	}
+
+	pub fn route(&mut self, l: &Value) -> Result<(), PipelineError> {
+		let tenant = get_tenant(l);
+		// Log on its shape
+		let con = &mut self.con;
+		match resolve(&self.routes, |tenant| con.get(tenant), l)? {
+			Resolution::Route(_, Some(topic)) => self.publish(l, topic)?,
+			Resolution::Route(_, None) => {}
+			Resolution::Tenant { topic, .. } => {
+				println!("Record for tenant: {}", tenant);
+				self.publish(l, topic)?;
+			}
+			Resolution::InvalidConfig(e) => {
+				println!("Cannot parse the configuration of {}: {}", tenant, e);
+			}
+			Resolution::Unknown => {
+				let conf_str = serde_json::to_string(&Config::new(tenant.clone())).unwrap();
+				let _: () = self.con.set(tenant.clone(), conf_str.clone())?;
+
+				let t_list: String = self.con.get("tenant_list")?;
+				let _: () = self
+					.con
+					.set("tenant_list", format!("{},{}", t_list, tenant))?;
+
+				let _: () = self.con.publish("tenant_config", conf_str)?;
+
+				println!(
+					"No config for tenant... {}, just created one, this log is dropped",
+					tenant
+				);
+			}
+		}
+
+		Ok(())
+	}
+}
+
+impl pipeline::Sink for Router {
+	fn write(&mut self, batch: &[Message]) -> Result<(), PipelineError> {
+		for message in batch {
+			match message.json() {
+				Ok(l) => self.route(&l)?,
+				Err(e) => println!("Dropping record {}: {}", message.id, e),
+			}
+		}
+
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::Config;
+
+	#[test]
+	fn draws_one_or_two_sinks() {
+		let counts: Vec<usize> = (0..100)
+			.map(|_| Config::new(s!("acme")).sinks.len())
+			.collect();
+
+		assert!(counts.iter().all(|n| (1..=2).contains(n)));
+		assert!(counts.contains(&2));
+	}
+}
@@ -0,0 +1,608 @@
+use futures::{
+	future::{self, loop_fn, Loop},
+	try_ready, Async, AsyncSink, Future, Poll, Sink, StartSend, Stream,
+};
+use rusoto_kinesis::{
+	GetRecordsOutput, GetShardIteratorOutput, Kinesis, PutRecordsInput, PutRecordsRequestEntry,
+	Shard,
+};
+use std::{
+	collections::{HashSet, VecDeque},
+	mem,
+	time::{Instant, SystemTime, UNIX_EPOCH},
+};
+use tokio_timer::Delay;
+
+use crate::kinesis::{
+	refused, FailedRecord, KinesisError, KinesisHandler, PartitionKeyStrategy, RetryPolicy,
+	ShardGraph, Start, StartPosition,
+};
+use crate::kpl::{user_records, UserRecord};
+use crate::producer::{
+	size, Batch, DeliveryReport, MAX_RECORD_BYTES, NOT_SERIALIZABLE, RECORD_TOO_LARGE,
+};
+
+/// A Kinesis call on its way, retries included.
+type Call<T> = Box<dyn Future<Item = T, Error = KinesisError> + Send>;
+
+fn timer(e: tokio_timer::Error) -> KinesisError {
+	KinesisError::Transport(format!("timer: {}", e))
+}
+
+/// Makes the call `call` starts again as `retry` says, while it fails with
+/// an error that may go away.
+fn retrying<T, C, F>(retry: RetryPolicy, mut call: C) -> Call<T>
+where
+	T: Send + 'static,
+	C: FnMut() -> F + Send + 'static,
+	F: Future<Item = T, Error = KinesisError> + Send + 'static,
+{
+	Box::new(loop_fn(1, move |attempt| {
+		let retry = retry.clone();
+		call().then(move |result| -> Call<Loop<T, u32>> {
+			match result {
+				Ok(output) => Box::new(future::ok(Loop::Break(output))),
+				Err(ref e) if e.is_retryable() && attempt < retry.attempts => Box::new(
+					Delay::new(Instant::now() + retry.backoff(attempt))
+						.map_err(timer)
+						.map(move |_| Loop::Continue(attempt + 1)),
+				),
+				Err(e) => Box::new(future::err(e)),
+			}
+		})
+	}))
+}
+
+/// Every shard of the stream, closed ones included, across all the pages
+/// of `ListShards`.
+fn list_shards(handler: &KinesisHandler) -> Call<Vec<Shard>> {
+	let handler = handler.clone();
+
+	Box::new(loop_fn(
+		(Vec::new(), None),
+		move |(mut shards, next_token): (Vec<Shard>, Option<String>)| {
+			let (client, input) = (handler.client(), handler.shards_input(next_token));
+			retrying(handler.retry().clone(), move || {
+				client
+					.list_shards(input.clone())
+					.map_err(KinesisError::from)
+			})
+			.map(move |page| {
+				shards.extend(page.shards.unwrap_or_default());
+				match page.next_token {
+					Some(token) => Loop::Continue((shards, Some(token))),
+					None => Loop::Break(shards),
+				}
+			})
+		},
+	))
+}
+
+enum State {
+	Iterator(Call<GetShardIteratorOutput>),
+	/// Records asked with an iterator, at the time given.
+	Records(Call<GetRecordsOutput>, Instant),
+	/// Waiting before asking records with an iterator.
+	Waiting(Delay, String),
+	/// Waiting before replacing an expired iterator.
+	Expired(Delay),
+	Closed,
+}
+
+/// Records of a shard as their producers put them, without a thread of its
+/// own: it polls `GetRecords` as the handler says once records run out.
+/// Ends once the shard is closed and read to its end, or after an error
+/// the retries did not get past. An expired iterator is replaced by one
+/// right after the last record read, or at the position the first one was
+/// asked at when none was read yet. Reading from `Latest` is then resumed at
+/// the time the first iterator was asked, so records put a little before it
+/// may come again rather than be skipped. Iterators are replaced after a
+/// backoff as the retries say, and the stream fails once they expired as
+/// many times in a row as the retries allow.
+pub struct ShardStream {
+	handler: KinesisHandler,
+	shard_id: String,
+	position: StartPosition,
+	state: State,
+	records: VecDeque<UserRecord>,
+	/// Calls in a row that got no records.
+	empty: u32,
+	/// Iterators in a row that expired before getting records.
+	expired: u32,
+}
+
+impl ShardStream {
+	pub fn new(handler: KinesisHandler, shard_id: String, position: StartPosition) -> Self {
+		let mut stream = ShardStream {
+			handler,
+			shard_id,
+			position,
+			state: State::Closed,
+			records: VecDeque::new(),
+			empty: 0,
+			expired: 0,
+		};
+		stream.state = stream.iterator();
+		stream
+	}
+
+	pub fn shard_id(&self) -> &str {
+		&self.shard_id
+	}
+
+	fn iterator(&mut self) -> State {
+		let client = self.handler.client();
+		let input = self
+			.handler
+			.iterator_input(self.shard_id.clone(), &self.position);
+		// Asking `Latest` again once the iterator expired would skip the
+		// records put meanwhile
+		if self.position == StartPosition::Latest {
+			let now = SystemTime::now()
+				.duration_since(UNIX_EPOCH)
+				.unwrap_or_default();
+			self.position = StartPosition::AtTimestamp(now.as_secs_f64());
+		}
+
+		State::Iterator(retrying(self.handler.retry().clone(), move || {
+			client
+				.get_shard_iterator(input.clone())
+				.map_err(KinesisError::from)
+		}))
+	}
+
+	fn records(&self, it: &str) -> State {
+		let client = self.handler.client();
+		let input = self.handler.records_input(it);
+
+		State::Records(
+			retrying(self.handler.retry().clone(), move || {
+				client
+					.get_records(input.clone())
+					.map_err(KinesisError::from)
+			}),
+			Instant::now(),
+		)
+	}
+}
+
+impl Stream for ShardStream {
+	type Item = UserRecord;
+	type Error = KinesisError;
+
+	fn poll(&mut self) -> Poll<Option<UserRecord>, KinesisError> {
+		loop {
+			if let Some(record) = self.records.pop_front() {
+				return Ok(Async::Ready(Some(record)));
+			}
+
+			self.state = match mem::replace(&mut self.state, State::Closed) {
+				State::Iterator(mut call) => match call.poll()? {
+					Async::NotReady => {
+						self.state = State::Iterator(call);
+						return Ok(Async::NotReady);
+					}
+					Async::Ready(output) => match output.shard_iterator {
+						Some(it) => self.records(&it),
+						None => {
+							let message = format!("no iterator for shard {}", self.shard_id);
+							return Err(KinesisError::NotFound(message));
+						}
+					},
+				},
+				State::Records(mut call, polled) => match call.poll() {
+					Ok(Async::NotReady) => {
+						self.state = State::Records(call, polled);
+						return Ok(Async::NotReady);
+					}
+					Err(KinesisError::ExpiredIterator(_))
+						if self.expired + 1 < self.handler.retry().attempts =>
+					{
+						self.expired += 1;
+						let backoff = self.handler.retry().backoff(self.expired);
+						State::Expired(Delay::new(Instant::now() + backoff))
+					}
+					Err(e) => return Err(e),
+					Ok(Async::Ready(rec)) => {
+						self.expired = 0;
+						self.empty = if rec.records.is_empty() {
+							self.empty + 1
+						} else {
+							0
+						};
+						let wait = self
+							.handler
+							.polling()
+							.wait(rec.millis_behind_latest, self.empty);
+						if let Some(last) = rec.records.last() {
+							self.position =
+								StartPosition::AfterSequenceNumber(last.sequence_number.clone());
+						}
+						self.records
+							.extend(rec.records.into_iter().flat_map(user_records));
+
+						// A closed shard has no next iterator once read to its end
+						match rec.next_shard_iterator {
+							Some(it) => State::Waiting(Delay::new(polled + wait), it),
+							None => State::Closed,
+						}
+					}
+				},
+				State::Waiting(mut delay, it) => match delay.poll().map_err(timer)? {
+					Async::NotReady => {
+						self.state = State::Waiting(delay, it);
+						return Ok(Async::NotReady);
+					}
+					Async::Ready(()) => self.records(&it),
+				},
+				State::Expired(mut delay) => match delay.poll().map_err(timer)? {
+					Async::NotReady => {
+						self.state = State::Expired(delay);
+						return Ok(Async::NotReady);
+					}
+					Async::Ready(()) => self.iterator(),
+				},
+				State::Closed => return Ok(Async::Ready(None)),
+			};
+		}
+	}
+}
+
+/// Records of every shard of a stream with the id of their shard, a shard
+/// created by a split or a merge only read once its parents were read to
+/// their end, like `get_records_stream` but on the task polling it. Shards
+/// are polled in turns so a busy one does not hold the others back. Ends
+/// once every shard is read to its end, or with the first error of a shard.
+pub struct MergedStream {
+	handler: KinesisHandler,
+	start: Start,
+	graph: Option<ShardGraph>,
+	/// Shards listed from the start, as opposed to those created since.
+	initial: HashSet<String>,
+	listing: Option<Call<Vec<Shard>>>,
+	shards: Vec<ShardStream>,
+	/// Shard polled first next time.
+	next: usize,
+}
+
+impl MergedStream {
+	pub fn new(handler: KinesisHandler, start: Start) -> Self {
+		let listing = match &start {
+			Start::Shard(..) => None,
+			_ => Some(list_shards(&handler)),
+		};
+		let shards = match &start {
+			Start::Shard(shard_id, position) => vec![ShardStream::new(
+				handler.clone(),
+				shard_id.clone(),
+				position.clone(),
+			)],
+			_ => vec![],
+		};
+
+		MergedStream {
+			handler,
+			start,
+			graph: None,
+			initial: HashSet::new(),
+			listing,
+			shards,
+			next: 0,
+		}
+	}
+
+	/// Starts reading the shards whose parents were read to their end.
+	fn open(&mut self, listed: Vec<Shard>) {
+		let graph = match &mut self.graph {
+			Some(graph) => {
+				graph.update(listed);
+				graph
+			}
+			None => {
+				self.initial = listed.iter().map(|s| s.shard_id.clone()).collect();
+				self.graph.get_or_insert(ShardGraph::new(listed))
+			}
+		};
+
+		loop {
+			let mut skipped = false;
+			for shard_id in graph.readable() {
+				if self.shards.iter().any(|s| s.shard_id == shard_id) {
+					continue;
+				}
+				let shard = graph.get(&shard_id).cloned().expect("Shard listed");
+				match self
+					.start
+					.position(&shard, self.initial.contains(&shard_id))
+				{
+					Some(position) => {
+						self.shards
+							.push(ShardStream::new(self.handler.clone(), shard_id, position))
+					}
+					None => {
+						graph.drained(&shard_id);
+						skipped = true;
+					}
+				}
+			}
+			if !skipped {
+				return;
+			}
+		}
+	}
+}
+
+impl Stream for MergedStream {
+	type Item = (String, UserRecord);
+	type Error = KinesisError;
+
+	fn poll(&mut self) -> Poll<Option<(String, UserRecord)>, KinesisError> {
+		loop {
+			if let Some(listing) = &mut self.listing {
+				let listed = try_ready!(listing.poll());
+				self.listing = None;
+				self.open(listed);
+			}
+
+			let count = self.shards.len();
+			if count == 0 {
+				return Ok(Async::Ready(None));
+			}
+			let mut closed = None;
+			for i in 0..count {
+				let index = (self.next + i) % count;
+				match self.shards[index].poll()? {
+					Async::Ready(Some(record)) => {
+						self.next = index + 1;
+						let shard_id = self.shards[index].shard_id.clone();
+						return Ok(Async::Ready(Some((shard_id, record))));
+					}
+					Async::Ready(None) => {
+						closed = Some(index);
+						break;
+					}
+					Async::NotReady => {}
+				}
+			}
+
+			let shard = match closed {
+				Some(index) => self.shards.remove(index),
+				None => return Ok(Async::NotReady),
+			};
+			if let Some(graph) = &mut self.graph {
+				graph.drained(&shard.shard_id);
+				// Its children can be read now
+				self.listing = Some(list_shards(&self.handler));
+			}
+		}
+	}
+}
+
+/// Puts the records sent to it in batches as large as Kinesis allows,
+/// retrying the records refused as the retry policy of the handler says,
+/// on the task driving it. A batch leaves once full or once the sink is
+/// flushed. Records still refused go to the dead letter callback, if any,
+/// and to the report, only a failure of a whole call is an error.
+pub struct PutSink<T> {
+	handler: KinesisHandler,
+	strategy: PartitionKeyStrategy,
+	batch: Batch,
+	sending: Option<Call<(usize, Vec<FailedRecord>)>>,
+	report: DeliveryReport,
+	item: std::marker::PhantomData<T>,
+}
+
+impl<T: serde::Serialize> PutSink<T> {
+	pub fn new(handler: KinesisHandler, strategy: PartitionKeyStrategy) -> Self {
+		PutSink {
+			handler,
+			strategy,
+			batch: Batch::new(),
+			sending: None,
+			report: DeliveryReport::default(),
+			item: std::marker::PhantomData,
+		}
+	}
+
+	/// What became of the records sent since the last report.
+	pub fn report(&mut self) -> DeliveryReport {
+		mem::take(&mut self.report)
+	}
+
+	/// Puts `entries`, then the ones refused again while attempts are
+	/// left. Tells how many were sent and which were still refused.
+	fn put(&self, entries: Vec<PutRecordsRequestEntry>) -> Call<(usize, Vec<FailedRecord>)> {
+		let (handler, count) = (self.handler.clone(), entries.len());
+
+		Box::new(
+			loop_fn((entries, 1), move |(entries, attempt)| {
+				let (client, retry) = (handler.client(), handler.retry().clone());
+				let input = PutRecordsInput {
+					records: entries.clone(),
+					stream_name: handler.stream().to_string(),
+				};
+				retrying(retry.clone(), move || {
+					client
+						.put_records(input.clone())
+						.map_err(KinesisError::from)
+				})
+				.and_then(move |output| -> Call<Loop<_, _>> {
+					let failed = refused(entries, output);
+					if attempt >= retry.attempts || failed.is_empty() {
+						return Box::new(future::ok(Loop::Break(failed)));
+					}
+					let entries = failed.into_iter().map(|f| f.entry).collect();
+					Box::new(
+						Delay::new(Instant::now() + retry.backoff(attempt))
+							.map_err(timer)
+							.map(move |_| Loop::Continue((entries, attempt + 1))),
+					)
+				})
+			})
+			.map(move |failed| (count, failed)),
+		)
+	}
+
+	/// Polls the batch being put, if any.
+	fn poll_sending(&mut self) -> Poll<(), KinesisError> {
+		if let Some(sending) = &mut self.sending {
+			let (count, failed) = try_ready!(sending.poll());
+			self.sending = None;
+			self.report.put += count - failed.len();
+			self.report.give_up(failed, &self.handler.dead_letter());
+		}
+		Ok(Async::Ready(()))
+	}
+}
+
+impl<T: serde::Serialize> Sink for PutSink<T> {
+	type SinkItem = T;
+	type SinkError = KinesisError;
+
+	fn start_send(&mut self, item: T) -> StartSend<T, KinesisError> {
+		let entry = match KinesisHandler::create_record_from(&item, &self.strategy) {
+			Ok(entry) => entry,
+			Err(e) => {
+				let record = FailedRecord {
+					entry: Default::default(),
+					error_code: s!(NOT_SERIALIZABLE),
+					error_message: e.to_string(),
+				};
+				self.report
+					.give_up(vec![record], &self.handler.dead_letter());
+				return Ok(AsyncSink::Ready);
+			}
+		};
+		if size(&entry) > MAX_RECORD_BYTES {
+			let record = FailedRecord {
+				error_code: s!(RECORD_TOO_LARGE),
+				error_message: format!(
+					"{} bytes, at most {} are allowed",
+					size(&entry),
+					MAX_RECORD_BYTES
+				),
+				entry,
+			};
+			self.report
+				.give_up(vec![record], &self.handler.dead_letter());
+			return Ok(AsyncSink::Ready);
+		}
+
+		if !self.batch.fits(&entry) {
+			if self.poll_sending()?.is_not_ready() {
+				return Ok(AsyncSink::NotReady(item));
+			}
+			let entries = self.batch.take();
+			self.sending = Some(self.put(entries));
+		}
+		self.batch.push(entry);
+		Ok(AsyncSink::Ready)
+	}
+
+	fn poll_complete(&mut self) -> Poll<(), KinesisError> {
+		loop {
+			try_ready!(self.poll_sending());
+			let entries = self.batch.take();
+			if entries.is_empty() {
+				return Ok(Async::Ready(()));
+			}
+			self.sending = Some(self.put(entries));
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{retrying, ShardStream, State};
+	use crate::kinesis::{KinesisError, KinesisHandler, RetryPolicy, StartPosition};
+	use futures::{future, Stream};
+	use std::{
+		collections::VecDeque,
+		sync::{
+			atomic::{AtomicUsize, Ordering},
+			Arc,
+		},
+		time::{Duration, Instant},
+	};
+	use tokio::runtime::{current_thread, Runtime};
+
+	#[test]
+	fn retries_calls_without_blocking() {
+		let retry = RetryPolicy {
+			attempts: 3,
+			base: Duration::from_millis(1),
+			max: Duration::from_millis(2),
+		};
+		let mut runtime = Runtime::new().unwrap();
+
+		let calls = Arc::new(AtomicUsize::new(0));
+		let counted = calls.clone();
+		let call = retrying(retry.clone(), move || {
+			match counted.fetch_add(1, Ordering::SeqCst) {
+				0 => future::err(KinesisError::Throttled(s!("slow down"))),
+				n => future::ok(n),
+			}
+		});
+		assert_eq!(1, runtime.block_on(call).unwrap());
+		assert_eq!(2, calls.load(Ordering::SeqCst));
+
+		let calls = Arc::new(AtomicUsize::new(0));
+		let counted = calls.clone();
+		let call = retrying(retry, move || {
+			counted.fetch_add(1, Ordering::SeqCst);
+			future::err::<(), _>(KinesisError::NotFound(s!("stream")))
+		});
+		assert!(runtime.block_on(call).is_err());
+		assert_eq!(1, calls.load(Ordering::SeqCst));
+	}
+
+	#[test]
+	fn resumes_at_the_time_latest_was_asked() {
+		let handler = KinesisHandler::new(s!("test"), None, Some("http://localhost:4568")).unwrap();
+		let stream = ShardStream::new(handler, s!("shardId-0"), StartPosition::Latest);
+
+		match stream.position {
+			StartPosition::AtTimestamp(time) => assert!(time > 0.0),
+			ref position => panic!("Expected a timestamp, got {:?}", position),
+		}
+	}
+
+	#[test]
+	fn backs_off_on_expired_iterators_until_the_retries_are_spent() {
+		let handler = KinesisHandler::new(s!("test"), None, Some("http://localhost:4568"))
+			.unwrap()
+			.with_retry(RetryPolicy {
+				attempts: 3,
+				base: Duration::from_secs(3600),
+				max: Duration::from_secs(3600),
+			});
+		let expiring = |expired| ShardStream {
+			handler: handler.clone(),
+			shard_id: s!("shardId-0"),
+			position: StartPosition::TrimHorizon,
+			state: State::Records(
+				Box::new(future::err(KinesisError::ExpiredIterator(s!("expired")))),
+				Instant::now(),
+			),
+			records: VecDeque::new(),
+			empty: 0,
+			expired,
+		};
+		// Delays need the timer of a runtime
+		let mut runtime = current_thread::Runtime::new().unwrap();
+		let mut poll = |mut stream: ShardStream| {
+			let polled = future::lazy(|| future::ok::<_, ()>(stream.poll()));
+			(runtime.block_on(polled).unwrap(), stream)
+		};
+
+		let (polled, stream) = poll(expiring(1));
+		assert!(polled.unwrap().is_not_ready());
+		assert!(matches!(stream.state, State::Expired(_)));
+		assert_eq!(2, stream.expired);
+
+		match poll(expiring(2)).0 {
+			Err(KinesisError::ExpiredIterator(_)) => {}
+			_ => panic!("Expected the iterator to have expired"),
+		}
+	}
+}
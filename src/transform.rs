@@ -0,0 +1,243 @@
+use serde_json::{Map, Value};
+
+use crate::config::{TransformAction, TransformConfig};
+
+fn split(path: &str) -> Vec<String> {
+	path.split('.').map(String::from).collect()
+}
+
+fn parent<'a>(
+	record: &'a mut Value,
+	path: &[String],
+	create: bool,
+) -> Option<&'a mut Map<String, Value>> {
+	let mut current = record;
+	for key in path {
+		let object = current.as_object_mut()?;
+		if create && !object.contains_key(key) {
+			object.insert(key.clone(), Value::Object(Map::new()));
+		}
+		current = object.get_mut(key)?;
+	}
+
+	current.as_object_mut()
+}
+
+fn take(record: &mut Value, path: &[String]) -> Option<Value> {
+	let (last, path) = path.split_last()?;
+	parent(record, path, false)?.remove(last)
+}
+
+/// Whether every field on the way to `path` is an object or missing.
+fn writable(record: &Value, path: &[String]) -> bool {
+	let mut current = Some(record);
+	for key in path.iter().take(path.len().saturating_sub(1)) {
+		match current {
+			Some(Value::Object(object)) => current = object.get(key),
+			Some(_) => return false,
+			None => return true,
+		}
+	}
+
+	current.is_none_or(Value::is_object)
+}
+
+/// Inserts `value` at `path`, hands it back untouched if a field on the way
+/// is not an object.
+fn put(record: &mut Value, path: &[String], value: Value) -> Result<(), Value> {
+	if !writable(record, path) {
+		return Err(value);
+	}
+	if let Some((last, path)) = path.split_last() {
+		if let Some(object) = parent(record, path, true) {
+			object.insert(last.clone(), value);
+			return Ok(());
+		}
+	}
+
+	Err(value)
+}
+
+enum Action {
+	Drop(Vec<String>),
+	Set(Vec<String>, Value),
+	Move(Vec<String>, Vec<String>),
+}
+
+struct Transform {
+	tenant: Option<String>,
+	log_type: Option<String>,
+	action: Action,
+}
+
+impl Transform {
+	fn selects(&self, record: &Value) -> bool {
+		let matches = |field: &str, expected: &Option<String>| match expected {
+			Some(expected) => record["metadata"][field].as_str() == Some(expected.as_str()),
+			None => true,
+		};
+
+		matches("tenant", &self.tenant) && matches("type", &self.log_type)
+	}
+
+	/// Whether the record changed, fields missing from it are left alone.
+	fn apply(&self, record: &mut Value) -> bool {
+		match &self.action {
+			Action::Move(from, to) => match take(record, from) {
+				// Taken first so a field can move under its own path
+				Some(value) => match put(record, to, value) {
+					Ok(()) => true,
+					Err(value) => {
+						let _ = put(record, from, value);
+						false
+					}
+				},
+				None => false,
+			},
+			Action::Drop(field) => take(record, field).is_some(),
+			Action::Set(field, value) => put(record, field, value.clone()).is_ok(),
+		}
+	}
+}
+
+/// The `transforms` of the configuration with their paths already split.
+#[derive(Default)]
+pub struct Transforms {
+	transforms: Vec<Transform>,
+}
+
+impl Transforms {
+	pub fn new(config: &[TransformConfig]) -> Self {
+		let transforms = config
+			.iter()
+			.map(|t| Transform {
+				tenant: t.tenant.clone(),
+				log_type: t.log_type.clone(),
+				action: match &t.action {
+					TransformAction::Rename(r) => {
+						let from = split(&r.from);
+						let mut to = from.clone();
+						to.pop();
+						to.push(r.to.clone());
+						Action::Move(from, to)
+					}
+					TransformAction::Drop(d) => Action::Drop(split(&d.field)),
+					TransformAction::Set(s) => Action::Set(split(&s.field), s.value.clone()),
+					TransformAction::Move(m) => Action::Move(split(&m.from), split(&m.to)),
+				},
+			})
+			.collect();
+
+		Transforms { transforms }
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.transforms.is_empty()
+	}
+
+	/// Applies, in order, every transform selecting the record. Selectors see
+	/// the record as left by the previous transforms.
+	pub fn apply(&self, record: &mut Value) {
+		for transform in self.transforms.iter() {
+			if transform.selects(record) {
+				transform.apply(record);
+			}
+		}
+	}
+
+	/// Same as `apply`, also returns the index of every transform that
+	/// changed the record.
+	pub fn trace(&self, record: &mut Value) -> Vec<usize> {
+		let mut applied = Vec::new();
+		for (i, transform) in self.transforms.iter().enumerate() {
+			if transform.selects(record) && transform.apply(record) {
+				applied.push(i);
+			}
+		}
+
+		applied
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::Transforms;
+	use crate::config::validate;
+	use serde_json::json;
+
+	fn transforms(yaml: &str) -> Transforms {
+		let config = validate(&format!("input: []\noutput: []\ntransforms:\n{}", yaml)).unwrap();
+		Transforms::new(&config.transforms)
+	}
+
+	#[test]
+	fn renames_drops_sets_and_moves_fields() {
+		let transforms = transforms(
+			"
+  - rename: { from: metadata.customer, to: tenant }
+  - drop: { field: debug }
+  - set: { field: metadata.source, value: kinesis }
+  - move: { from: payload.user.id, to: user.id }
+",
+		);
+		let mut record = json!({
+			"metadata": { "customer": "acme" },
+			"debug": true,
+			"payload": { "user": { "id": 7, "name": "ana" } },
+		});
+
+		transforms.apply(&mut record);
+
+		assert_eq!(
+			json!({
+				"metadata": { "tenant": "acme", "source": "kinesis" },
+				"payload": { "user": { "name": "ana" } },
+				"user": { "id": 7 },
+			}),
+			record
+		);
+	}
+
+	#[test]
+	fn only_changes_the_selected_records() {
+		let transforms = transforms(
+			"
+  - set: { field: metadata.type, value: access }
+    tenant: acme
+    type: nginx
+  - rename: { from: msg, to: message }
+    type: access
+",
+		);
+		let mut acme = json!({ "metadata": { "tenant": "acme", "type": "nginx" }, "msg": "GET /" });
+		let mut other =
+			json!({ "metadata": { "tenant": "other", "type": "nginx" }, "msg": "GET /" });
+
+		transforms.apply(&mut acme);
+		transforms.apply(&mut other);
+
+		assert_eq!(
+			json!({ "metadata": { "tenant": "acme", "type": "access" }, "message": "GET /" }),
+			acme
+		);
+		assert_eq!(
+			json!({ "metadata": { "tenant": "other", "type": "nginx" }, "msg": "GET /" }),
+			other
+		);
+	}
+
+	#[test]
+	fn leaves_fields_alone_when_the_target_is_not_an_object() {
+		let transforms = transforms(
+			"
+  - move: { from: payload.id, to: user.id }
+  - set: { field: user.name.first, value: ana }
+",
+		);
+		let mut record = json!({ "user": "ana", "payload": { "id": 7 } });
+
+		transforms.apply(&mut record);
+
+		assert_eq!(json!({ "user": "ana", "payload": { "id": 7 } }), record);
+	}
+}